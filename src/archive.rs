@@ -0,0 +1,353 @@
+use std::fs;
+use std::path::Path;
+
+use crate::checksum::crc32;
+use crate::inflate::inflate;
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4B50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4B50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4B50;
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+const ZIP_MAX_COMMENT: usize = 0xFFFF;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+// Sizes and offsets that only fit in the ZIP64 extension
+const ZIP64_MARKER: u32 = 0xFFFF_FFFF;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+const GZIP_HEADER_CRC: u8 = 0x02;
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+
+const ROM_EXTENSIONS: [&str; 2] = ["gb", "gbc"];
+
+pub struct Rom {
+    // The path the ROM would have if it sat next to its archive, so saves
+    // are named after the game rather than the archive
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+// Reads a plain ROM, a gzipped one, or an entry from a zip archive. The
+// first .gb or .gbc entry is used unless one is named.
+pub fn read_rom(path: &str, entry: Option<&str>) -> Result<Rom, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    if is_zip(&data) {
+        let (name, data) = read_zip(&data, entry).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(Rom { path: beside(path, &name), data })
+    }
+    else if data.starts_with(GZIP_MAGIC) {
+        let (name, data) = read_gzip(&data).map_err(|err| format!("{}: {}", path, err))?;
+        // Without a stored name game.gb.gz holds game.gb
+        let name = name.unwrap_or_else(|| Path::new(path).file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        Ok(Rom { path: beside(path, &name), data })
+    }
+    else if entry.is_some() {
+        Err(format!("{} is not a zip archive", path))
+    }
+    else {
+        Ok(Rom { path: path.to_string(), data })
+    }
+}
+
+// Reads a single named file out of a zip archive.
+pub fn zip_entry(data: &[u8], name: &str) -> Result<Vec<u8>, String> {
+    read_zip(data, Some(name)).map(|(_, contents)| contents)
+}
+
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(&ZIP_LOCAL_HEADER.to_le_bytes())
+}
+
+fn beside(archive_path: &str, name: &str) -> String {
+    let file_name = Path::new(name).file_name().unwrap_or_default();
+    Path::new(archive_path).with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+fn is_rom(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ROM_EXTENSIONS.iter().any(|rom| extension.eq_ignore_ascii_case(rom)))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    let bytes = data.get(offset..offset + 2).ok_or("Archive ends early")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = data.get(offset..offset + 4).ok_or("Archive ends early")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    header_offset: usize,
+}
+
+fn read_zip(data: &[u8], wanted: Option<&str>) -> Result<(String, Vec<u8>), String> {
+    let entries = zip_directory(data)?;
+
+    let entry = match wanted {
+        Some(wanted) => entries.iter().find(|entry| entry.name == wanted),
+        None => entries.iter().find(|entry| is_rom(&entry.name)),
+    };
+
+    let Some(entry) = entry else {
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        return Err(match wanted {
+            Some(wanted) => format!("No entry {}, the archive holds {}", wanted, names.join(", ")),
+            None => format!("No .gb or .gbc ROM, pick one of {} with --zip-entry", names.join(", ")),
+        });
+    };
+
+    let header = entry.header_offset;
+    if u32_at(data, header)? != ZIP_LOCAL_HEADER {
+        return Err(format!("Corrupt header for {}", entry.name));
+    }
+
+    // The local header's name and extra field can differ from the directory's
+    let start = header + ZIP_LOCAL_HEADER_SIZE + u16_at(data, header + 26)? as usize + u16_at(data, header + 28)? as usize;
+    let compressed = data.get(start..start + entry.compressed_size).ok_or("Archive ends early")?;
+
+    let contents = match entry.method {
+        METHOD_STORED => compressed.to_vec(),
+        METHOD_DEFLATE => inflate(compressed)?,
+        method => return Err(format!("{} uses unsupported compression method {}", entry.name, method)),
+    };
+
+    if contents.len() != entry.size || crc32(&contents) != entry.crc {
+        return Err(format!("{} is corrupt, its checksum doesn't match", entry.name));
+    }
+
+    Ok((entry.name.clone(), contents))
+}
+
+// Lists the files in the central directory, found through the record at
+// the end of the archive which can be followed by a comment.
+fn zip_directory(data: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    let earliest = data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE + ZIP_MAX_COMMENT);
+    let end = (earliest..=data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE))
+        .rev()
+        .find(|&offset| u32_at(data, offset) == Ok(ZIP_END_OF_DIRECTORY))
+        .ok_or("Not a complete zip archive")?;
+
+    let count = u16_at(data, end + 10)? as usize;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    let mut entries = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if u32_at(data, offset)? != ZIP_CENTRAL_HEADER {
+            return Err("Corrupt zip directory".to_string());
+        }
+
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let name = data.get(offset + ZIP_CENTRAL_HEADER_SIZE..offset + ZIP_CENTRAL_HEADER_SIZE + name_length).ok_or("Archive ends early")?;
+
+        let (compressed_size, size, header_offset) = (u32_at(data, offset + 20)?, u32_at(data, offset + 24)?, u32_at(data, offset + 42)?);
+        if [compressed_size, size, header_offset].contains(&ZIP64_MARKER) {
+            return Err("ZIP64 archives aren't supported".to_string());
+        }
+
+        let entry = ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(data, offset + 10)?,
+            crc: u32_at(data, offset + 16)?,
+            compressed_size: compressed_size as usize,
+            size: size as usize,
+            header_offset: header_offset as usize,
+        };
+
+        offset += ZIP_CENTRAL_HEADER_SIZE + name_length + u16_at(data, offset + 30)? as usize + u16_at(data, offset + 32)? as usize;
+
+        // Directories have names ending in a slash and nothing in them
+        if !entry.name.ends_with('/') {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+// A single gzip member, returning the original file name if it was stored.
+fn read_gzip(data: &[u8]) -> Result<(Option<String>, Vec<u8>), String> {
+    if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE {
+        return Err("Gzip file ends early".to_string());
+    }
+    if data[2] != METHOD_DEFLATE as u8 {
+        return Err(format!("Unsupported gzip compression method {}", data[2]));
+    }
+
+    let flags = data[3];
+    let mut offset = GZIP_HEADER_SIZE;
+
+    if flags & GZIP_EXTRA != 0 {
+        offset += 2 + u16_at(data, offset)? as usize;
+    }
+
+    let field = |offset: &mut usize| -> Result<String, String> {
+        let length = data.get(*offset..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or("Gzip file ends early")?;
+        let text = String::from_utf8_lossy(&data[*offset..*offset + length]).into_owned();
+        *offset += length + 1;
+        Ok(text)
+    };
+
+    let name = if flags & GZIP_NAME != 0 { Some(field(&mut offset)?) } else { None };
+    if flags & GZIP_COMMENT != 0 {
+        field(&mut offset)?;
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        offset += 2;
+    }
+
+    let trailer = data.len() - GZIP_TRAILER_SIZE;
+    let contents = inflate(data.get(offset..trailer).ok_or("Gzip file ends early")?)?;
+
+    if crc32(&contents) != u32_at(data, trailer)? || contents.len() as u32 != u32_at(data, trailer + 4)? {
+        return Err("Gzip file is corrupt, its checksum doesn't match".to_string());
+    }
+
+    Ok((name, contents))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // "GAME" 16 times, deflated
+    const DEFLATED_ROM: &[u8] = &[0x73, 0x77, 0xF4, 0x75, 0x75, 0xA7, 0x00, 0x03, 0x00];
+
+    fn rom() -> Vec<u8> {
+        b"GAME".repeat(16)
+    }
+
+    pub(crate) struct Entry<'a> {
+        name: &'a str,
+        method: u16,
+        stored: &'a [u8],
+        crc: u32,
+        size: usize,
+    }
+
+    fn entry<'a>(name: &'a str, method: u16, stored: &'a [u8], contents: &[u8]) -> Entry<'a> {
+        Entry { name, method, stored, crc: crc32(contents), size: contents.len() }
+    }
+
+    pub(crate) fn stored<'a>(name: &'a str, contents: &'a [u8]) -> Entry<'a> {
+        entry(name, METHOD_STORED, contents, contents)
+    }
+
+    pub(crate) fn zip(entries: &[Entry]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+
+        for entry in entries {
+            let offset = data.len() as u32;
+            let sizes = [entry.crc, entry.stored.len() as u32, entry.size as u32];
+
+            data.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0]);
+            data.extend_from_slice(&entry.method.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            sizes.iter().for_each(|value| data.extend_from_slice(&value.to_le_bytes()));
+            data.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(entry.name.as_bytes());
+            data.extend_from_slice(entry.stored);
+
+            directory.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            sizes.iter().for_each(|value| directory.extend_from_slice(&value.to_le_bytes()));
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&ZIP_END_OF_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    fn gzip(name: Option<&str>, crc: u32) -> Vec<u8> {
+        let mut data = GZIP_MAGIC.to_vec();
+        data.push(METHOD_DEFLATE as u8);
+        data.push(if name.is_some() { GZIP_NAME } else { 0 });
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0xFF]);
+        if let Some(name) = name {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        data.extend_from_slice(DEFLATED_ROM);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(&(rom().len() as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn zip_picks_the_first_rom() {
+        let archive = zip(&[
+            entry("docs/", METHOD_STORED, b"", b""),
+            entry("readme.txt", METHOD_STORED, b"Have fun", b"Have fun"),
+            entry("Game.GBC", METHOD_DEFLATE, DEFLATED_ROM, &rom()),
+        ]);
+
+        assert!(is_zip(&archive));
+        assert_eq!(read_zip(&archive, None).unwrap(), ("Game.GBC".to_string(), rom()));
+        assert_eq!(zip_entry(&archive, "readme.txt").unwrap(), b"Have fun");
+    }
+
+    #[test]
+    fn zip_names_its_entries_when_one_is_missing() {
+        let archive = zip(&[entry("readme.txt", METHOD_STORED, b"Have fun", b"Have fun")]);
+
+        assert_eq!(read_zip(&archive, None).unwrap_err(), "No .gb or .gbc ROM, pick one of readme.txt with --zip-entry");
+        assert_eq!(zip_entry(&archive, "game.gb").unwrap_err(), "No entry game.gb, the archive holds readme.txt");
+    }
+
+    #[test]
+    fn zip_checks_the_crc() {
+        let mut corrupt = entry("game.gb", METHOD_DEFLATE, DEFLATED_ROM, &rom());
+        corrupt.crc ^= 1;
+
+        assert_eq!(read_zip(&zip(&[corrupt]), None).unwrap_err(), "game.gb is corrupt, its checksum doesn't match");
+    }
+
+    #[test]
+    fn gzip_keeps_the_stored_name() {
+        assert_eq!(read_gzip(&gzip(Some("game.gb"), crc32(&rom()))).unwrap(), (Some("game.gb".to_string()), rom()));
+        assert_eq!(read_gzip(&gzip(None, crc32(&rom()))).unwrap(), (None, rom()));
+    }
+
+    #[test]
+    fn gzip_checks_the_crc() {
+        assert!(read_gzip(&gzip(None, crc32(&rom()) ^ 1)).is_err());
+    }
+
+    #[test]
+    fn archive_roms_are_named_beside_the_archive() {
+        assert_eq!(beside("roms/pack.zip", "games/Tetris.gb"), Path::new("roms/Tetris.gb").to_string_lossy());
+        assert!(is_rom("tetris.GB"));
+        assert!(!is_rom("tetris.sav"));
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const ROM_END: u16 = 0x7FFF;
+const IO_START: u16 = 0xFF00;
+const IO_END: u16 = 0xFF7F;
+const INTERRUPT_ENABLE: u16 = 0xFFFF;
+const WRAM_BANKED_START: u16 = 0xD000;
+const WRAM_BANKED_END: u16 = 0xDFFF;
+const GAMESHARK_WRITE: u8 = 0x01;
+// GameShark codes of type 9X write to WRAM bank X on the CGB
+const GAMESHARK_WRAM_BANK: u8 = 0x90;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    // Replaces a ROM byte as it is read, only when the original matches
+    // the compare byte if there is one.
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+    // Writes a RAM byte once a frame.
+    GameShark { bank: Option<usize>, addr: u16, value: u8 },
+}
+
+impl Code {
+    // Game Genie codes are ABC-DEF or ABC-DEF-GHI, GameShark codes TTVVAAAA.
+    pub fn parse(text: &str) -> Option<Code> {
+        let nibbles: Vec<u8> = text.chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<_>>()?;
+
+        let nibble = |index: usize| nibbles[index] as u16;
+        let byte = |index: usize| nibbles[index] << 4 | nibbles[index + 1];
+
+        match nibbles.len() {
+            6 | 9 => {
+                // The address is FCDE with the top nibble inverted
+                let addr = (nibble(5) ^ 0xF) << 12 | nibble(2) << 8 | nibble(3) << 4 | nibble(4);
+                if addr > ROM_END {
+                    return None;
+                }
+
+                // The compare byte is GI, XORed with 0xBA and rotated left by two
+                let compare = (nibbles.len() == 9).then(|| (nibbles[6] << 4 | nibbles[8]).rotate_right(2) ^ 0xBA);
+
+                Some(Code::GameGenie { addr, value: byte(0), compare })
+            }
+            // Dashes only belong in Game Genie codes
+            8 if !text.contains('-') => {
+                let kind = byte(0);
+                let addr = u16::from_le_bytes([byte(4), byte(6)]);
+                // Writing ROM or the I/O registers directly would skip what the
+                // hardware does on a write, so only RAM can be set
+                if addr <= ROM_END || (IO_START..=IO_END).contains(&addr) || addr == INTERRUPT_ENABLE {
+                    return None;
+                }
+
+                let banked = kind & 0xF0 == GAMESHARK_WRAM_BANK && (WRAM_BANKED_START..=WRAM_BANKED_END).contains(&addr);
+                // Bank 0 can't be mapped at 0xD000, selecting it gives bank 1
+                let bank = banked.then(|| ((kind & 0x07) as usize).max(1));
+
+                Some(Code::GameShark { bank, addr, value: byte(2) })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Code::GameGenie { addr, value, compare } => {
+                let nibbles = [
+                    value >> 4, value & 0xF, (addr >> 8) as u8 & 0xF,
+                    (addr >> 4) as u8 & 0xF, addr as u8 & 0xF, (addr >> 12) as u8 ^ 0xF,
+                ];
+                write!(f, "{:X}{:X}{:X}-{:X}{:X}{:X}", nibbles[0], nibbles[1], nibbles[2], nibbles[3], nibbles[4], nibbles[5])?;
+
+                if let Some(compare) = compare {
+                    let encoded = (compare ^ 0xBA).rotate_left(2);
+                    // The middle digit isn't used, it's conventionally the inverse of the first
+                    write!(f, "-{:X}{:X}{:X}", encoded >> 4, (encoded >> 4) ^ 0xF, encoded & 0xF)?;
+                }
+                Ok(())
+            }
+            Code::GameShark { bank, addr, value } => {
+                let kind = match bank {
+                    Some(bank) => GAMESHARK_WRAM_BANK | bank as u8,
+                    None => GAMESHARK_WRITE,
+                };
+                let [low, high] = addr.to_le_bytes();
+                write!(f, "{:02X}{:02X}{:02X}{:02X}", kind, value, low, high)
+            }
+        }
+    }
+}
+
+pub struct Cheat {
+    pub code: Code,
+    pub name: String,
+    pub enabled: bool,
+}
+
+// Cheats from a file next to the ROM with one code per line, optionally
+// followed by a name. Lines starting with # are comments.
+#[derive(Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn load(path: &Path) -> Result<Cheats, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut cheats = Cheats::default();
+
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let code = Code::parse(code).ok_or_else(|| format!("Invalid cheat code {}", code))?;
+
+            cheats.cheats.push(Cheat {
+                code,
+                name: if name.trim().is_empty() { line.to_string() } else { name.trim().to_string() },
+                enabled: true,
+            });
+        }
+
+        Ok(cheats)
+    }
+
+    // Loads game.cht for game.gb if there is one.
+    pub fn next_to(rom_path: &str) -> Cheats {
+        let path = Path::new(rom_path).with_extension("cht");
+        if !path.exists() {
+            return Cheats::default();
+        }
+
+        Cheats::load(&path).unwrap_or_else(|err| {
+            eprintln!("Cheats: {}: {}", path.display(), err);
+            Cheats::default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn toggle(&mut self, index: usize) -> Option<&Cheat> {
+        let cheat = self.cheats.get_mut(index)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat)
+    }
+
+    // Turns every cheat off, or back on if they were all off already.
+    pub fn toggle_all(&mut self) -> bool {
+        let enabled = !self.cheats.iter().any(|cheat| cheat.enabled);
+        for cheat in &mut self.cheats {
+            cheat.enabled = enabled;
+        }
+        enabled
+    }
+
+    // The byte the CPU sees when reading value from ROM at addr.
+    pub fn patch_read(&self, addr: u16, value: u8) -> u8 {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            if let Code::GameGenie { addr: code_addr, value: new_value, compare } = cheat.code {
+                if code_addr == addr && compare.is_none_or(|compare| compare == value) {
+                    return new_value;
+                }
+            }
+        }
+
+        value
+    }
+
+    pub fn frame_writes(&self) -> Vec<(Option<usize>, u16, u8)> {
+        self.cheats.iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.code {
+                Code::GameShark { bank, addr, value } => Some((bank, addr, value)),
+                Code::GameGenie { .. } => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_genie_codes() {
+        assert_eq!(Code::parse("01C-A2E"), Some(Code::GameGenie { addr: 0x1CA2, value: 0x01, compare: None }));
+        // GI is 0x3A, rotated right by two and XORed with 0xBA
+        assert_eq!(Code::parse("01C-A2E-3CA"), Some(Code::GameGenie { addr: 0x1CA2, value: 0x01, compare: Some(0x34) }));
+        assert_eq!(Code::parse("01ca2e"), Code::parse("01C-A2E"));
+    }
+
+    #[test]
+    fn gameshark_codes() {
+        assert_eq!(Code::parse("01FF16C0"), Some(Code::GameShark { bank: None, addr: 0xC016, value: 0xFF }));
+        assert_eq!(Code::parse("9363A0D2"), Some(Code::GameShark { bank: Some(3), addr: 0xD2A0, value: 0x63 }));
+        // Bank 0 selects bank 1, and only the switchable half is banked
+        assert_eq!(Code::parse("9063A0D2"), Some(Code::GameShark { bank: Some(1), addr: 0xD2A0, value: 0x63 }));
+        assert_eq!(Code::parse("9363A0C2"), Some(Code::GameShark { bank: None, addr: 0xC2A0, value: 0x63 }));
+    }
+
+    #[test]
+    fn invalid_codes() {
+        for text in ["", "01C-A2", "01C-A2E-3C", "01FF16C", "G1FF16C0", "01C-A27", "01FF3412", "01FF40FF", "01FFFFFF"] {
+            assert_eq!(Code::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for text in ["01C-A2E", "FF0-12E-3CA", "00A-17B-C39", "01FF16C0", "9363A0D2"] {
+            let code = Code::parse(text).unwrap();
+            assert_eq!(code.to_string(), text);
+            assert_eq!(Code::parse(&code.to_string()), Some(code));
+        }
+
+        // The middle compare digit is ignored and written as the inverse of the first
+        assert_eq!(Code::parse("00A-17B-C49").unwrap().to_string(), "00A-17B-C39");
+    }
+
+    #[test]
+    fn compare_byte_limits_game_genie_patches() {
+        let mut cheats = Cheats::default();
+        cheats.cheats.push(Cheat { code: Code::parse("01C-A2E-3CA").unwrap(), name: String::new(), enabled: true });
+
+        assert_eq!(cheats.patch_read(0x1CA2, 0x34), 0x01);
+        assert_eq!(cheats.patch_read(0x1CA2, 0x35), 0x35);
+        assert_eq!(cheats.patch_read(0x1CA3, 0x34), 0x34);
+
+        cheats.toggle_all();
+        assert_eq!(cheats.patch_read(0x1CA2, 0x34), 0x34);
+    }
+}
@@ -0,0 +1,203 @@
+// Colours the CGB boot ROM gives to monochrome games. It hashes the title of
+// games published by Nintendo to pick a combination of background and sprite
+// palettes, anything it doesn't recognise gets the default.
+
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const NEW_LICENSEE: usize = 0x0144;
+const OLD_LICENSEE: usize = 0x014B;
+const USE_NEW_LICENSEE: u8 = 0x33;
+const NINTENDO: u8 = 0x01;
+
+const DEFAULT_COMBINATION: usize = 0;
+
+// Each line is one palette of 15-bit colours
+const COLOURS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+const fn palettes(obj0: usize, obj1: usize, bg: usize) -> [usize; 3] {
+    [obj0 * 4, obj1 * 4, bg * 4]
+}
+
+// OBJ0, OBJ1 and BG as offsets into COLOURS. A few combinations start a
+// palette one colour early, reusing the last colour of the one before it.
+const COMBINATIONS: [[usize; 3]; 51] = [
+    palettes(4, 4, 29),
+    palettes(18, 18, 18),
+    palettes(20, 20, 20),
+    palettes(24, 24, 24),
+    palettes(9, 9, 9),
+    palettes(0, 0, 0),
+    palettes(27, 27, 27),
+    palettes(5, 5, 5),
+    palettes(12, 12, 12),
+    palettes(26, 26, 26),
+    palettes(16, 8, 8),
+    palettes(4, 28, 28),
+    palettes(4, 2, 2),
+    palettes(3, 4, 4),
+    palettes(4, 29, 29),
+    palettes(28, 4, 28),
+    palettes(2, 17, 2),
+    palettes(16, 16, 8),
+    palettes(4, 4, 7),
+    palettes(4, 4, 18),
+    palettes(4, 4, 20),
+    palettes(19, 19, 9),
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    palettes(17, 17, 2),
+    palettes(4, 4, 2),
+    palettes(4, 4, 3),
+    palettes(28, 28, 0),
+    palettes(3, 3, 0),
+    palettes(0, 0, 1),
+    palettes(18, 22, 18),
+    palettes(20, 22, 20),
+    palettes(24, 22, 24),
+    palettes(16, 22, 8),
+    palettes(17, 4, 13),
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    palettes(19, 22, 9),
+    palettes(16, 28, 10),
+    palettes(4, 23, 28),
+    palettes(17, 22, 2),
+    palettes(4, 0, 2),
+    palettes(4, 28, 3),
+    palettes(28, 3, 0),
+    palettes(3, 28, 4),
+    palettes(21, 28, 4),
+    palettes(3, 28, 0),
+    palettes(25, 3, 28),
+    palettes(0, 28, 8),
+    palettes(4, 3, 28),
+    palettes(28, 3, 6),
+    palettes(4, 28, 29),
+];
+
+// Combinations picked by holding a direction and optionally A or B while
+// the boot ROM logo is shown.
+const BUTTON_COMBINATIONS: [(&str, usize); 12] = [
+    ("up", 5),
+    ("up-a", 43),
+    ("up-b", 28),
+    ("left", 48),
+    ("left-a", 40),
+    ("left-b", 7),
+    ("down", 8),
+    ("down-a", 3),
+    ("down-b", 49),
+    ("right", 1),
+    ("right-a", 0),
+    ("right-b", 6),
+];
+
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0xC3, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    // From here on several titles share a checksum and are told apart by
+    // the fourth letter of the title
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const FIRST_DUPLICATE: usize = 65;
+const DISAMBIGUATION_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+pub const COMBINATION_COUNT: usize = COMBINATIONS.len();
+
+// Picks the combination the boot ROM would for the cartridge header.
+pub fn lookup_combination(cartridge: &[u8]) -> usize {
+    let Some(checksum) = title_checksum(cartridge) else {
+        return DEFAULT_COMBINATION;
+    };
+    let fourth_letter = cartridge[TITLE_START + 3];
+
+    TITLE_CHECKSUMS.iter()
+        .enumerate()
+        .position(|(index, &value)| {
+            value == checksum
+                && (index < FIRST_DUPLICATE || DISAMBIGUATION_LETTERS[index - FIRST_DUPLICATE] == fourth_letter)
+        })
+        .map_or(DEFAULT_COMBINATION, |index| CHECKSUM_COMBINATIONS[index] as usize)
+}
+
+// The boot ROM only hashes titles of games published by Nintendo.
+pub fn title_checksum(cartridge: &[u8]) -> Option<u8> {
+    if cartridge.len() <= OLD_LICENSEE || !licensed_by_nintendo(cartridge) {
+        return None;
+    }
+
+    Some(cartridge[TITLE_START..=TITLE_END].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)))
+}
+
+fn licensed_by_nintendo(cartridge: &[u8]) -> bool {
+    match cartridge[OLD_LICENSEE] {
+        USE_NEW_LICENSEE => &cartridge[NEW_LICENSEE..NEW_LICENSEE + 2] == b"01",
+        licensee => licensee == NINTENDO,
+    }
+}
+
+// Accepts a button combination such as "left-b" or a combination number.
+pub fn parse_combination(value: &str) -> Option<usize> {
+    if let Some((_, combination)) = BUTTON_COMBINATIONS.iter().find(|(name, _)| *name == value) {
+        return Some(*combination);
+    }
+
+    value.parse().ok().filter(|combination| *combination < COMBINATION_COUNT)
+}
+
+// Returns the BG, OBJ0 and OBJ1 palettes of a combination.
+pub fn combination_palettes(combination: usize) -> [[u16; 4]; 3] {
+    let [obj0, obj1, bg] = COMBINATIONS[combination];
+    let palette = |offset: usize| {
+        let mut colours = [0; 4];
+        colours.copy_from_slice(&COLOURS[offset..offset + 4]);
+        colours
+    };
+
+    [palette(bg), palette(obj0), palette(obj1)]
+}
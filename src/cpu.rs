@@ -1,5 +1,7 @@
 use std::u8;
+use crate::disassembler;
 use crate::memory::*;
+use crate::state::{StateReader, StateWriter};
 
 const Z_FLAG : u8 = 7;
 const N_FLAG : u8 = 6;
@@ -158,6 +160,14 @@ impl Register {
     }
 }
 
+pub struct Registers {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub pc: u16,
+}
+
 pub struct CPU {
     ticks: u32,
     register_af: Register,
@@ -191,16 +201,58 @@ impl CPU {
         self.program_counter = pc
     }
 
+    pub fn registers(&self) -> Registers {
+        Registers {
+            af: self.register_af.get(),
+            bc: self.register_bc.get(),
+            de: self.register_de.get(),
+            hl: self.register_hl.get(),
+            pc: self.program_counter,
+        }
+    }
+
+    pub fn set_registers(&mut self, af: u16, bc: u16, de: u16, hl: u16) {
+        self.register_af.set(af);
+        self.register_bc.set(bc);
+        self.register_de.set(de);
+        self.register_hl.set(hl);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.ticks);
+        writer.write_u16(self.register_af.get());
+        writer.write_u16(self.register_bc.get());
+        writer.write_u16(self.register_de.get());
+        writer.write_u16(self.register_hl.get());
+        writer.write_u16(self.program_counter);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ticks = reader.read_u32()?;
+        self.register_af.set(reader.read_u16()?);
+        self.register_bc.set(reader.read_u16()?);
+        self.register_de.set(reader.read_u16()?);
+        self.register_hl.set(reader.read_u16()?);
+        self.program_counter = reader.read_u16()?;
+
+        Ok(())
+    }
+
     pub fn execute_opcode(&mut self,  memory_bus: &mut Memory) {
         let opcode = self.fetch_opcode(memory_bus);
 
-        println!("Program Counter {:X}", self.program_counter);
-        println!("Opcode {:X}", opcode);
+        let n = memory_bus.peek_memory((self.program_counter + 1)as usize);
 
-        let n = memory_bus.read_memory((self.program_counter + 1)as usize);
+        let nn = (memory_bus.peek_memory((self.program_counter + 2) as usize) as u16) << 8 | 
+                 (memory_bus.peek_memory((self.program_counter + 1) as usize) as u16);
 
-        let nn = (memory_bus.read_memory((self.program_counter + 2) as usize) as u16) << 8 | 
-                 (memory_bus.read_memory((self.program_counter + 1) as usize) as u16);
+        // Only the operand bytes the instruction has count as reads
+        if memory_bus.has_watchpoints() {
+            let instruction = disassembler::decode(self.program_counter, |addr| memory_bus.peek_memory(addr as usize));
+            for offset in 1..instruction.length {
+                memory_bus.read_memory(self.program_counter.wrapping_add(offset) as usize);
+            }
+        }
 
            let pc_change = match opcode {
             0x00 => self.opcode_nop(),
@@ -210,9 +262,10 @@ impl CPU {
             0x0C => self.opcode_inc_c(),
             0x0D => self.opcode_dec_c(),
             0x0E => self.opcode_load_cn(n),
+            0x10 => self.opcode_stop(memory_bus),
             0x11 => self.opcode_load_de_16(nn),
             0x12 => self.opcode_load_de_a(memory_bus),
-            0x13 => self.opcode_dec_de(),
+            0x13 => self.opcode_dec_de(memory_bus),
             0x15 => self.opcode_dec_d(),
             0x16 => self.opcode_load_d_n(n),
             0x17 => self.opcode_roate_a_left(),
@@ -224,7 +277,7 @@ impl CPU {
             0x20 => self.opcode_jmp_nz(n as i8),
             0x21 => self.opcode_load_hl_16(nn),
             0x22 => self.opcode_load_a_hl_inc(memory_bus),
-            0x23 => self.opcode_inc_hl(),
+            0x23 => self.opcode_inc_hl(memory_bus),
             0x24 => self.opcode_inc_h(),
             0x28 => self.opcode_jr_nz(n as i8),
             0x2A => self.opcode_load_hl_a_inc(memory_bus),
@@ -429,6 +482,20 @@ impl CPU {
         ProgramCounter::Skip
     }
 
+    fn opcode_stop(&mut self, memory_bus: &mut Memory) -> ProgramCounter {
+        // On the CGB a prepared speed switch happens here, which takes 2050
+        // M-cycles. Otherwise STOP waits for a button press, and without
+        // a joypad it carries on as a NOP.
+        if memory_bus.try_speed_switch() {
+            self.ticks += 8200;
+        }
+        else {
+            self.ticks += 4;
+        }
+
+        ProgramCounter::Skip
+    }
+
     fn opcode_load_de_16(&mut self, value: u16) -> ProgramCounter {
         self.register_de.set(value);
         self.ticks += 12;
@@ -444,7 +511,8 @@ impl CPU {
         ProgramCounter::Next
     }
 
-    fn opcode_dec_de(&mut self) -> ProgramCounter {
+    fn opcode_dec_de(&mut self, memory_bus: &mut Memory) -> ProgramCounter {
+        memory_bus.oam_bug_inc_dec(self.register_de.get());
         self.register_de.dec();
         self.ticks += 8;
 
@@ -560,7 +628,8 @@ impl CPU {
         ProgramCounter::Skip2
     }
 
-    fn opcode_inc_hl(&mut self) -> ProgramCounter {
+    fn opcode_inc_hl(&mut self, memory_bus: &mut Memory) -> ProgramCounter {
+        memory_bus.oam_bug_inc_dec(self.register_hl.get());
         self.register_hl.inc();
         self.ticks += 8;
 
@@ -600,6 +669,7 @@ impl CPU {
         memory_bus.write_memory(self.register_hl.get() as usize,
                                      self.register_af.get_left());
 
+        memory_bus.oam_bug_inc_dec(self.register_hl.get());
         self.register_hl.inc();
         self.ticks += 8;
 
@@ -632,6 +702,7 @@ impl CPU {
     fn opcode_load_hl_a_dec(&mut self, memory_bus: &mut Memory) -> ProgramCounter {
         memory_bus.write_memory(self.register_hl.get() as usize, 
                                      self.register_af.get_left());
+        memory_bus.oam_bug_inc_dec(self.register_hl.get());
         self.register_hl.dec();
         self.ticks += 8;
 
@@ -1338,4 +1409,35 @@ impl CPU {
 
         ProgramCounter::Jump(value)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch_reads(memory: &mut Memory, addr: u16) {
+        memory.watchpoints_mut().push(Watchpoint { start: addr, end: addr, read: true, write: false });
+    }
+
+    #[test]
+    fn operands_an_instruction_lacks_are_not_watched_reads() {
+        let mut cpu = CPU::new();
+        let mut memory = Memory::new();
+        watch_reads(&mut memory, 0xC001);
+
+        // A NOP followed by data
+        cpu.set_program_counter(0xC000);
+        memory.write_internal(0xC001, 0x42);
+        cpu.execute_opcode(&mut memory);
+        assert!(memory.take_watch_hit().is_none());
+
+        // LD B, $42 reads the byte after it
+        memory.write_internal(0xC000, 0x06);
+        cpu.set_program_counter(0xC000);
+        cpu.execute_opcode(&mut memory);
+
+        let hit = memory.take_watch_hit().unwrap();
+        assert_eq!((hit.addr, hit.write, hit.value), (0xC001, false, 0x42));
+        assert_eq!(cpu.registers().bc >> 8, 0x42);
+    }
 }
\ No newline at end of file
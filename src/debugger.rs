@@ -0,0 +1,478 @@
+use std::any::Any;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::cpu::CPU;
+use crate::disassembler;
+use crate::memory::{Memory, Watchpoint};
+use crate::options::parse_hex;
+use crate::symbols::Symbols;
+
+const HELP: &str = "\
+break <addr|bank:addr>   stop when PC reaches the address or label
+delete <n>               remove breakpoint or watchpoint n
+watch <addr[-end]>       stop on writes
+rwatch <addr[-end]>      stop on reads
+awatch <addr[-end]>      stop on reads and writes
+list                     show breakpoints and watchpoints
+step [count]             run one or more instructions
+next                     step over calls
+finish                   run until the current routine returns
+continue                 run until something stops execution
+regs                     show the registers
+set <reg> <value>        change a register
+x <addr> [length]        dump memory
+poke <addr> <value>      change a byte of memory
+disasm [addr] [count]    disassemble, around PC by default
+quit                     leave dustboy";
+
+const ROM_END: u16 = 0x7FFF;
+const DUMP_WIDTH: usize = 16;
+const DEFAULT_DUMP_LENGTH: usize = 64;
+const DEFAULT_DISASSEMBLY: usize = 10;
+// How far back to look for an instruction boundary ending at PC
+const DISASSEMBLY_LOOKBEHIND: u16 = 12;
+const INSTRUCTIONS_BEFORE_PC: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+struct Breakpoint {
+    bank: Option<usize>,
+    addr: u16,
+}
+
+enum RunMode {
+    Stopped,
+    Continue,
+    Step(usize),
+    // Run until PC returns to the instruction after a call
+    StepOver { addr: u16, stack_pointer: u16 },
+    // Run until a return leaves the stack above where it started
+    StepOut { stack_pointer: u16 },
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: RunMode,
+    // Skips breakpoints on the instruction execution resumes from
+    resuming: bool,
+    returning: bool,
+    symbols: Symbols,
+}
+
+// Something that can stop the CPU between instructions, either the prompt
+// below or a gdb connection.
+pub trait Control {
+    // Returns false once the user asks to quit.
+    fn before_instruction(&mut self, cpu: &mut CPU, memory: &mut Memory) -> bool;
+    fn execute(&mut self, cpu: &mut CPU, memory: &mut Memory);
+}
+
+impl Control for Debugger {
+    // Runs the prompt if execution should stop here.
+    fn before_instruction(&mut self, cpu: &mut CPU, memory: &mut Memory) -> bool {
+        let pc = cpu.registers().pc;
+        let stack_pointer = memory.get_stack_pointer();
+        let returned = self.returning;
+        self.returning = disassembler::decode(pc, |addr| memory.read_internal(addr as usize)).text.starts_with("ret");
+
+        let resuming = self.resuming;
+        self.resuming = false;
+
+        if !resuming {
+            if let Some(index) = self.breakpoints.iter().position(|breakpoint| self.breakpoint_hit(breakpoint, pc, memory)) {
+                match self.symbols.mapped_label(memory, pc) {
+                    Some(name) => println!("Breakpoint {} at {} ({})", index, format_address(pc, memory), name),
+                    None => println!("Breakpoint {} at {}", index, format_address(pc, memory)),
+                }
+                self.mode = RunMode::Stopped;
+            }
+        }
+
+        let stop = match self.mode {
+            RunMode::Stopped => true,
+            RunMode::Continue => false,
+            RunMode::Step(count) => {
+                self.mode = if count > 1 { RunMode::Step(count - 1) } else { RunMode::Stopped };
+                false
+            }
+            RunMode::StepOver { addr, stack_pointer: start } => pc == addr && stack_pointer >= start,
+            RunMode::StepOut { stack_pointer: start } => returned && stack_pointer > start,
+        };
+
+        if !stop {
+            return true;
+        }
+
+        self.mode = RunMode::Stopped;
+        self.prompt(cpu, memory)
+    }
+
+    // Runs the instruction, stopping instead of crashing if it panics.
+    fn execute(&mut self, cpu: &mut CPU, memory: &mut Memory) {
+        // Drop accesses made outside the instruction, such as by the trace log
+        memory.take_watch_hit();
+
+        if let Err(message) = execute_guarded(cpu, memory) {
+            println!("Stopped: {}", message);
+            self.mode = RunMode::Stopped;
+            self.resuming = true;
+            return;
+        }
+
+        if let Some(hit) = memory.take_watch_hit() {
+            let access = if hit.write { "Write" } else { "Read" };
+            println!("{} of ${:02X} at ${:04X}", access, hit.value, hit.addr);
+            self.mode = RunMode::Stopped;
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new(symbols: Symbols) -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: RunMode::Stopped,
+            resuming: false,
+            returning: false,
+            symbols,
+        }
+    }
+
+    fn breakpoint_hit(&self, breakpoint: &Breakpoint, pc: u16, memory: &Memory) -> bool {
+        breakpoint.addr == pc && breakpoint.bank.is_none_or(|bank| memory.rom_bank(pc) == Some(bank))
+    }
+
+    fn prompt(&mut self, cpu: &mut CPU, memory: &mut Memory) -> bool {
+        self.print_disassembly(cpu.registers().pc, 1, memory);
+
+        let stdin = io::stdin();
+
+        loop {
+            print!("(dustboy) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, arguments)) = words.split_first() else {
+                continue;
+            };
+
+            let result = match command {
+                "help" | "h" => {
+                    println!("{}", HELP);
+                    Ok(())
+                }
+                "break" | "b" => self.add_breakpoint(arguments),
+                "delete" | "del" => self.delete(arguments, memory),
+                "watch" => self.add_watchpoint(arguments, false, true, memory),
+                "rwatch" => self.add_watchpoint(arguments, true, false, memory),
+                "awatch" => self.add_watchpoint(arguments, true, true, memory),
+                "list" | "l" => {
+                    self.list(memory);
+                    Ok(())
+                }
+                "step" | "s" => match arguments.first().map(|count| count.parse::<usize>()) {
+                    Some(Ok(count)) if count > 0 => return self.resume(RunMode::Step(count)),
+                    Some(_) => Err("Expected a number of instructions".to_string()),
+                    None => return self.resume(RunMode::Step(1)),
+                },
+                "next" | "n" => {
+                    let pc = cpu.registers().pc;
+                    let instruction = disassembler::decode(pc, |addr| memory.read_internal(addr as usize));
+
+                    let mode = if instruction.text.starts_with("call") || instruction.text.starts_with("rst") {
+                        RunMode::StepOver { addr: pc.wrapping_add(instruction.length), stack_pointer: memory.get_stack_pointer() }
+                    }
+                    else {
+                        RunMode::Step(1)
+                    };
+                    return self.resume(mode);
+                }
+                "finish" | "f" => return self.resume(RunMode::StepOut { stack_pointer: memory.get_stack_pointer() }),
+                "continue" | "c" => return self.resume(RunMode::Continue),
+                "regs" | "r" => {
+                    print_registers(cpu, memory);
+                    Ok(())
+                }
+                "set" => set_register(arguments, cpu, memory),
+                "x" => dump_memory(arguments, memory, &self.symbols),
+                "poke" => poke(arguments, memory, &self.symbols),
+                "disasm" | "d" => self.disassemble(arguments, cpu, memory),
+                "quit" | "q" => return false,
+                _ => Err(format!("Unknown command {}, try help", command)),
+            };
+
+            if let Err(err) = result {
+                println!("{}", err);
+            }
+        }
+    }
+
+    fn resume(&mut self, mode: RunMode) -> bool {
+        self.mode = mode;
+        self.resuming = true;
+        true
+    }
+
+    fn add_breakpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let value = arguments.first().ok_or("Expected an address")?;
+
+        let breakpoint = match (value.split_once(':'), self.symbols.resolve(value)) {
+            // A label in ROM only breaks in the bank it was placed in
+            (_, Some((bank, addr))) => Breakpoint { bank: (addr <= ROM_END).then_some(bank), addr },
+            (Some((bank, addr)), None) => Breakpoint {
+                bank: Some(usize::from_str_radix(bank, 16).map_err(|_| format!("Invalid bank {}", bank))?),
+                addr: parse_address(&self.symbols, addr)?,
+            },
+            (None, None) => Breakpoint { bank: None, addr: parse_address(&self.symbols, value)? },
+        };
+
+        self.breakpoints.push(breakpoint);
+        println!("Breakpoint {} at {}", self.breakpoints.len() - 1, value);
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, arguments: &[&str], read: bool, write: bool, memory: &mut Memory) -> Result<(), String> {
+        let value = arguments.first().ok_or("Expected an address")?;
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (parse_address(&self.symbols, start)?, parse_address(&self.symbols, end)?),
+            None => (parse_address(&self.symbols, value)?, parse_address(&self.symbols, value)?),
+        };
+
+        memory.watchpoints_mut().push(Watchpoint { start, end, read, write });
+        println!("Watchpoint {} on {}", self.breakpoints.len() + memory.watchpoints_mut().len() - 1, value);
+        Ok(())
+    }
+
+    // Breakpoints are numbered first, then watchpoints.
+    fn delete(&mut self, arguments: &[&str], memory: &mut Memory) -> Result<(), String> {
+        let index: usize = arguments.first()
+            .and_then(|index| index.parse().ok())
+            .ok_or("Expected a breakpoint number")?;
+
+        if index < self.breakpoints.len() {
+            self.breakpoints.remove(index);
+        }
+        else if index - self.breakpoints.len() < memory.watchpoints_mut().len() {
+            memory.watchpoints_mut().remove(index - self.breakpoints.len());
+        }
+        else {
+            return Err(format!("No breakpoint {}", index));
+        }
+
+        Ok(())
+    }
+
+    fn list(&self, memory: &mut Memory) {
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            match breakpoint.bank {
+                Some(bank) => println!("{}: break {:02X}:{:04X}", index, bank, breakpoint.addr),
+                None => println!("{}: break {:04X}", index, breakpoint.addr),
+            }
+        }
+
+        for (index, watchpoint) in memory.watchpoints_mut().iter().enumerate() {
+            let kind = match (watchpoint.read, watchpoint.write) {
+                (true, true) => "awatch",
+                (true, false) => "rwatch",
+                _ => "watch",
+            };
+            println!("{}: {} {:04X}-{:04X}", self.breakpoints.len() + index, kind, watchpoint.start, watchpoint.end);
+        }
+    }
+
+    fn disassemble(&self, arguments: &[&str], cpu: &CPU, memory: &Memory) -> Result<(), String> {
+        let count = match arguments.get(1) {
+            Some(count) => count.parse().map_err(|_| format!("Invalid count {}", count))?,
+            None => DEFAULT_DISASSEMBLY,
+        };
+
+        match arguments.first() {
+            Some(addr) => self.print_disassembly(parse_address(&self.symbols, addr)?, count, memory),
+            None => {
+                let pc = cpu.registers().pc;
+                let start = instructions_before(pc, memory);
+                let before = start_distance(start, pc, memory);
+                self.print_disassembly(start, before + count, memory);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn print_disassembly(&self, from: u16, count: usize, memory: &Memory) {
+        let read = |addr: u16| memory.read_internal(addr as usize);
+        let mut addr = from;
+
+        let label = |addr: u16| self.symbols.mapped_label(memory, addr).map(|name| name.to_string());
+
+        for _ in 0..count {
+            if let Some(name) = label(addr) {
+                println!("{}:", name);
+            }
+
+            let instruction = disassembler::decode_with_labels(addr, read, label);
+            let marker = if self.breakpoints.iter().any(|breakpoint| self.breakpoint_hit(breakpoint, addr, memory)) { "*" } else { " " };
+
+            println!("{} {}  {}", marker, format_address(addr, memory), instruction.text);
+            addr = addr.wrapping_add(instruction.length);
+        }
+    }
+}
+
+fn format_address(addr: u16, memory: &Memory) -> String {
+    match memory.rom_bank(addr) {
+        Some(bank) => format!("{:02X}:{:04X}", bank, addr),
+        None => format!("{:04X}", addr),
+    }
+}
+
+// Instructions have no markers, so look for the earliest start from which
+// decoding lands exactly on PC.
+fn instructions_before(pc: u16, memory: &Memory) -> u16 {
+    for distance in (1..=DISASSEMBLY_LOOKBEHIND).rev() {
+        let start = pc.wrapping_sub(distance);
+        let mut addr = start;
+        let mut instructions = 0;
+
+        while addr != pc && pc.wrapping_sub(addr) <= distance {
+            addr = addr.wrapping_add(disassembler::decode(addr, |addr| memory.read_internal(addr as usize)).length);
+            instructions += 1;
+        }
+
+        if addr == pc && instructions <= INSTRUCTIONS_BEFORE_PC {
+            return start;
+        }
+    }
+
+    pc
+}
+
+fn start_distance(start: u16, pc: u16, memory: &Memory) -> usize {
+    let mut addr = start;
+    let mut instructions = 0;
+
+    while addr != pc {
+        addr = addr.wrapping_add(disassembler::decode(addr, |addr| memory.read_internal(addr as usize)).length);
+        instructions += 1;
+    }
+
+    instructions
+}
+
+fn parse_address(symbols: &Symbols, value: &str) -> Result<u16, String> {
+    symbols.parse_address(value).ok_or_else(|| format!("Invalid address or unknown label {}", value))
+}
+
+fn print_registers(cpu: &CPU, memory: &Memory) {
+    let registers = cpu.registers();
+    let flags = registers.af as u8;
+    let flag = |bit: u8, name: char| if flags & bit != 0 { name } else { '-' };
+
+    println!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} [{}{}{}{}]",
+        registers.af,
+        registers.bc,
+        registers.de,
+        registers.hl,
+        memory.get_stack_pointer(),
+        registers.pc,
+        flag(0x80, 'Z'),
+        flag(0x40, 'N'),
+        flag(0x20, 'H'),
+        flag(0x10, 'C')
+    );
+}
+
+fn set_register(arguments: &[&str], cpu: &mut CPU, memory: &mut Memory) -> Result<(), String> {
+    let (Some(name), Some(value)) = (arguments.first(), arguments.get(1)) else {
+        return Err("Expected a register and a value".to_string());
+    };
+    let value = parse_hex(value).ok_or_else(|| format!("Invalid value {}", value))?;
+
+    let mut registers = cpu.registers();
+    let high = |pair: u16| (pair & 0x00FF) | (value & 0xFF) << 8;
+    let low = |pair: u16| (pair & 0xFF00) | (value & 0xFF);
+
+    match name.to_ascii_lowercase().as_str() {
+        "a" => registers.af = high(registers.af),
+        // The low nibble of F always reads as zero
+        "f" => registers.af = low(registers.af) & 0xFFF0,
+        "b" => registers.bc = high(registers.bc),
+        "c" => registers.bc = low(registers.bc),
+        "d" => registers.de = high(registers.de),
+        "e" => registers.de = low(registers.de),
+        "h" => registers.hl = high(registers.hl),
+        "l" => registers.hl = low(registers.hl),
+        "af" => registers.af = value & 0xFFF0,
+        "bc" => registers.bc = value,
+        "de" => registers.de = value,
+        "hl" => registers.hl = value,
+        "sp" => memory.set_stack_pointer(value),
+        "pc" => registers.pc = value,
+        _ => return Err(format!("Unknown register {}", name)),
+    }
+
+    cpu.set_registers(registers.af, registers.bc, registers.de, registers.hl);
+    cpu.set_program_counter(registers.pc);
+    Ok(())
+}
+
+// Dumps through the internal path so reading doesn't trip watchpoints.
+fn dump_memory(arguments: &[&str], memory: &Memory, symbols: &Symbols) -> Result<(), String> {
+    let start = parse_address(symbols, arguments.first().ok_or("Expected an address")?)?;
+    let length = match arguments.get(1) {
+        Some(length) => parse_hex(length).ok_or_else(|| format!("Invalid length {}", length))? as usize,
+        None => DEFAULT_DUMP_LENGTH,
+    };
+
+    for row in (0..length).step_by(DUMP_WIDTH) {
+        let bytes: Vec<u8> = (row..(row + DUMP_WIDTH).min(length))
+            .map(|offset| memory.read_internal(start.wrapping_add(offset as u16) as usize))
+            .collect();
+
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = bytes.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+
+        println!("{:04X}  {:<47}  {}", start.wrapping_add(row as u16), hex.join(" "), text);
+    }
+
+    Ok(())
+}
+
+fn poke(arguments: &[&str], memory: &mut Memory, symbols: &Symbols) -> Result<(), String> {
+    let (Some(addr), Some(value)) = (arguments.first(), arguments.get(1)) else {
+        return Err("Expected an address and a value".to_string());
+    };
+
+    let addr = parse_address(symbols, addr)?;
+    let value = parse_hex(value).filter(|value| *value <= 0xFF).ok_or_else(|| format!("Invalid byte {}", value))?;
+
+    memory.write_internal(addr as usize, value as u8);
+    Ok(())
+}
+
+// Runs an instruction, turning a panic such as an unimplemented opcode into
+// an error so the game can be inspected.
+pub fn execute_guarded(cpu: &mut CPU, memory: &mut Memory) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(|| cpu.execute_opcode(memory)))
+        .map_err(|payload| panic_message(payload.as_ref()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    }
+    else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    }
+    else {
+        "the emulator panicked".to_string()
+    }
+}
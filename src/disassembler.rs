@@ -0,0 +1,242 @@
+// Decodes LR35902 instructions into RGBDS syntax.
+
+use crate::symbols::Symbols;
+
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const REGISTER_PAIRS: [&str; 4] = ["bc", "de", "hl", "sp"];
+const STACK_PAIRS: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp"];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACCUMULATOR: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+const BANK_SIZE: usize = 0x4000;
+const SWITCHABLE_START: u16 = 0x4000;
+const ROM_END: u16 = 0x7FFF;
+
+pub struct Instruction {
+    pub length: u16,
+    pub text: String,
+}
+
+impl Instruction {
+    fn new(length: u16, text: String) -> Self {
+        Instruction {
+            length,
+            text,
+        }
+    }
+}
+
+// Decodes the instruction at addr, reading its bytes through read.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Instruction {
+    decode_with_labels(addr, read, |_| None)
+}
+
+// As decode, with jump targets and memory operands named by label when
+// it has one for them.
+pub fn decode_with_labels<F, L>(addr: u16, read: F, label: L) -> Instruction
+where
+    F: Fn(u16) -> u8,
+    L: Fn(u16) -> Option<String>,
+{
+    let opcode = read(addr);
+    let n = read(addr.wrapping_add(1));
+    let nn = (read(addr.wrapping_add(2)) as u16) << 8 | n as u16;
+    let relative = addr.wrapping_add(2).wrapping_add(n as i8 as u16);
+    let target = |value: u16| label(value).unwrap_or_else(|| format!("${:04X}", value));
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = (opcode & 0x07) as usize;
+    let p = y >> 1;
+    let q = y & 0x01;
+
+    let one = |text: String| Instruction::new(1, text);
+    let two = |text: String| Instruction::new(2, text);
+    let three = |text: String| Instruction::new(3, text);
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => one("nop".to_string()),
+            1 => three(format!("ld [{}], sp", target(nn))),
+            // STOP is followed by a byte the CPU skips
+            2 => two("stop".to_string()),
+            3 => two(format!("jr {}", target(relative))),
+            _ => two(format!("jr {}, {}", CONDITIONS[y - 4], target(relative))),
+        },
+        (0, 1) if q == 0 => three(format!("ld {}, ${:04X}", REGISTER_PAIRS[p], nn)),
+        (0, 1) => one(format!("add hl, {}", REGISTER_PAIRS[p])),
+        (0, 2) => {
+            let memory = ["[bc]", "[de]", "[hl+]", "[hl-]"][p];
+
+            if q == 0 { one(format!("ld {}, a", memory)) } else { one(format!("ld a, {}", memory)) }
+        }
+        (0, 3) if q == 0 => one(format!("inc {}", REGISTER_PAIRS[p])),
+        (0, 3) => one(format!("dec {}", REGISTER_PAIRS[p])),
+        (0, 4) => one(format!("inc {}", REGISTERS[y])),
+        (0, 5) => one(format!("dec {}", REGISTERS[y])),
+        (0, 6) => two(format!("ld {}, ${:02X}", REGISTERS[y], n)),
+        (0, _) => one(ACCUMULATOR[y].to_string()),
+        (1, 6) if y == 6 => one("halt".to_string()),
+        (1, _) => one(format!("ld {}, {}", REGISTERS[y], REGISTERS[z])),
+        (2, _) => one(format!("{} {}", ALU[y], REGISTERS[z])),
+        (3, 0) => match y {
+            0..=3 => one(format!("ret {}", CONDITIONS[y])),
+            4 => two(format!("ldh [{}], a", target(0xFF00 | n as u16))),
+            5 => two(format!("add sp, {}", n as i8)),
+            6 => two(format!("ldh a, [{}]", target(0xFF00 | n as u16))),
+            _ => two(format!("ld hl, sp{:+}", n as i8)),
+        },
+        (3, 1) if q == 0 => one(format!("pop {}", STACK_PAIRS[p])),
+        (3, 1) => one(["ret", "reti", "jp hl", "ld sp, hl"][p].to_string()),
+        (3, 2) => match y {
+            0..=3 => three(format!("jp {}, {}", CONDITIONS[y], target(nn))),
+            4 => one("ldh [c], a".to_string()),
+            5 => three(format!("ld [{}], a", target(nn))),
+            6 => one("ldh a, [c]".to_string()),
+            _ => three(format!("ld a, [{}]", target(nn))),
+        },
+        (3, 3) => match y {
+            0 => three(format!("jp {}", target(nn))),
+            1 => two(decode_cb(n)),
+            6 => one("di".to_string()),
+            7 => one("ei".to_string()),
+            _ => invalid(opcode),
+        },
+        (3, 4) if y < 4 => three(format!("call {}, {}", CONDITIONS[y], target(nn))),
+        (3, 5) if q == 0 => one(format!("push {}", STACK_PAIRS[p])),
+        (3, 5) if p == 0 => three(format!("call {}", target(nn))),
+        (3, 6) => two(format!("{} ${:02X}", ALU[y], n)),
+        (3, 7) => one(format!("rst ${:02X}", y * 8)),
+        _ => invalid(opcode),
+    }
+}
+
+fn decode_cb(opcode: u8) -> String {
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let register = REGISTERS[(opcode & 0x07) as usize];
+
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATES[y], register),
+        1 => format!("bit {}, {}", y, register),
+        2 => format!("res {}, {}", y, register),
+        _ => format!("set {}, {}", y, register),
+    }
+}
+
+// Opcodes the CPU doesn't have, shown as data
+fn invalid(opcode: u8) -> Instruction {
+    Instruction::new(1, format!("db ${:02X}", opcode))
+}
+
+// Prints a listing of a ROM bank as the CPU would see it mapped, bank 0 at
+// 0x0000 and the chosen bank at 0x4000.
+pub fn print_listing(rom: &[u8], bank: usize, from: u16, count: Option<usize>, symbols: &Symbols) -> Result<(), String> {
+    if bank * BANK_SIZE >= rom.len() {
+        return Err(format!("Bank {} is beyond the end of the ROM", bank));
+    }
+
+    let read = |addr: u16| {
+        let offset = if addr < SWITCHABLE_START {
+            addr as usize
+        }
+        else {
+            bank.max(1) * BANK_SIZE + (addr - SWITCHABLE_START) as usize
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    };
+
+    let shown_bank = |addr: u16| if addr < SWITCHABLE_START { 0 } else { bank.max(1) };
+    let label = |addr: u16| {
+        let bank = if addr <= ROM_END { Some(shown_bank(addr)) } else { None };
+        symbols.label(bank, addr).map(|name| name.to_string())
+    };
+
+    let mut addr = from;
+    let mut printed = 0;
+
+    while addr <= ROM_END && count.is_none_or(|count| printed < count) {
+        if let Some(name) = label(addr) {
+            println!("{}:", name);
+        }
+
+        let instruction = decode_with_labels(addr, read, label);
+        let bytes: Vec<String> = (0..instruction.length)
+            .map(|offset| format!("{:02X}", read(addr.wrapping_add(offset))))
+            .collect();
+
+        println!("{:02X}:{:04X}  {:<9} {}", shown_bank(addr), addr, bytes.join(" "), instruction.text);
+
+        addr = match addr.checked_add(instruction.length) {
+            Some(next) => next,
+            None => break,
+        };
+        printed += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> (u16, String) {
+        let instruction = decode(0x0100, |addr| bytes.get((addr - 0x0100) as usize).copied().unwrap_or(0));
+        (instruction.length, instruction.text)
+    }
+
+    #[test]
+    fn decodes_each_operand_form() {
+        let cases: &[(&[u8], u16, &str)] = &[
+            (&[0x00], 1, "nop"),
+            (&[0x01, 0x34, 0x12], 3, "ld bc, $1234"),
+            (&[0x08, 0x00, 0xC0], 3, "ld [$C000], sp"),
+            (&[0x10, 0x00], 2, "stop"),
+            (&[0x18, 0xFE], 2, "jr $0100"),
+            (&[0x20, 0x05], 2, "jr nz, $0107"),
+            (&[0x2A], 1, "ld a, [hl+]"),
+            (&[0x36, 0x7F], 2, "ld [hl], $7F"),
+            (&[0x76], 1, "halt"),
+            (&[0x78], 1, "ld a, b"),
+            (&[0x98], 1, "sbc a, b"),
+            (&[0xE0, 0x40], 2, "ldh [$FF40], a"),
+            (&[0xE8, 0xFE], 2, "add sp, -2"),
+            (&[0xF8, 0x02], 2, "ld hl, sp+2"),
+            (&[0xF1], 1, "pop af"),
+            (&[0xD9], 1, "reti"),
+            (&[0xFA, 0x00, 0xD0], 3, "ld a, [$D000]"),
+            (&[0xCD, 0x50, 0x01], 3, "call $0150"),
+            (&[0xDC, 0x50, 0x01], 3, "call c, $0150"),
+            (&[0xFE, 0x90], 2, "cp $90"),
+            (&[0xFF], 1, "rst $38"),
+        ];
+
+        for &(bytes, length, text) in cases {
+            assert_eq!(decode_bytes(bytes), (length, text.to_string()), "{:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn decodes_cb_prefixed_instructions() {
+        assert_eq!(decode_bytes(&[0xCB, 0x37]), (2, "swap a".to_string()));
+        assert_eq!(decode_bytes(&[0xCB, 0x7C]), (2, "bit 7, h".to_string()));
+        assert_eq!(decode_bytes(&[0xCB, 0x86]), (2, "res 0, [hl]".to_string()));
+        assert_eq!(decode_bytes(&[0xCB, 0xFF]), (2, "set 7, a".to_string()));
+    }
+
+    #[test]
+    fn missing_opcodes_are_data() {
+        for opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            assert_eq!(decode_bytes(&[opcode]), (1, format!("db ${:02X}", opcode)));
+        }
+    }
+
+    #[test]
+    fn names_targets_by_label() {
+        let bytes = [0xC3, 0x50, 0x01];
+        let instruction = decode_with_labels(0, |addr| bytes[addr as usize], |addr| (addr == 0x0150).then(|| "Main".to_string()));
+        assert_eq!(instruction.text, "jp Main");
+    }
+}
@@ -0,0 +1,593 @@
+use std::io::{BufReader, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::CPU;
+use crate::debugger::{self, Control};
+use crate::memory::{Memory, Watchpoint};
+
+// gdb has no description of the Game Boy CPU, so the stub sends its own.
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target version="1.0"><feature name="org.dustboy.sm83">"#,
+    r#"<reg name="af" bitsize="16" type="int"/>"#,
+    r#"<reg name="bc" bitsize="16" type="int"/>"#,
+    r#"<reg name="de" bitsize="16" type="int"/>"#,
+    r#"<reg name="hl" bitsize="16" type="int"/>"#,
+    r#"<reg name="sp" bitsize="16" type="data_ptr"/>"#,
+    r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#,
+    r#"</feature></target>"#
+);
+
+const REGISTER_COUNT: usize = 6;
+const MAX_PACKET_SIZE: usize = 0x1000;
+// Checking the socket for an interrupt on every instruction would be slow
+const INTERRUPT_POLL_INSTRUCTIONS: u32 = 4096;
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum BreakpointKind {
+    Software,
+    Hardware,
+}
+
+#[derive(PartialEq)]
+enum RunMode {
+    Stopped,
+    Continue,
+    Step,
+}
+
+enum Resume {
+    Run(RunMode),
+    Detach,
+    Kill,
+}
+
+// Speaks the gdb remote serial protocol over TCP. Registers are sent as
+// af, bc, de, hl, sp and pc, each 16 bits and little endian.
+pub struct GdbStub {
+    reader: Option<BufReader<TcpStream>>,
+    writer: Option<TcpStream>,
+    breakpoints: Vec<(u16, BreakpointKind)>,
+    mode: RunMode,
+    resuming: bool,
+    no_ack: bool,
+    // Reply to '?', the reason execution last stopped
+    stop_reply: String,
+    // Whether gdb is waiting to hear about the stop
+    announce: bool,
+    instructions: u32,
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> Result<GdbStub, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        println!("Waiting for gdb connection on port {}", port);
+
+        let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        GdbStub::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<GdbStub, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        let writer = stream.try_clone().map_err(|e| e.to_string())?;
+
+        Ok(GdbStub {
+            reader: Some(BufReader::new(stream)),
+            writer: Some(writer),
+            breakpoints: Vec::new(),
+            mode: RunMode::Stopped,
+            resuming: false,
+            no_ack: false,
+            stop_reply: format!("S{:02x}", SIGTRAP),
+            announce: false,
+            instructions: 0,
+        })
+    }
+
+    // Lets the game carry on without the debugger when gdb goes away.
+    fn disconnect(&mut self, memory: &mut Memory) {
+        self.reader = None;
+        self.writer = None;
+        self.mode = RunMode::Continue;
+        self.breakpoints.clear();
+        memory.watchpoints_mut().clear();
+    }
+
+    fn stop(&mut self, reply: String) {
+        self.mode = RunMode::Stopped;
+        self.stop_reply = reply;
+        self.announce = true;
+    }
+
+    fn interrupt_requested(&mut self) -> bool {
+        let Some(reader) = self.reader.as_mut() else {
+            return false;
+        };
+
+        let mut byte = [0];
+        let _ = reader.get_ref().set_nonblocking(true);
+        let result = reader.read(&mut byte);
+        let _ = reader.get_ref().set_nonblocking(false);
+
+        matches!(result, Ok(1)) && byte[0] == INTERRUPT
+    }
+
+    fn read_byte(&mut self) -> Result<u8, String> {
+        let reader = self.reader.as_mut().ok_or("gdb disconnected")?;
+        let mut byte = [0];
+
+        match reader.read(&mut byte) {
+            Ok(1) => Ok(byte[0]),
+            Ok(_) => Err("gdb disconnected".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Waits for the next packet, skipping acknowledgements and interrupts
+    // that arrive while already stopped.
+    fn read_packet(&mut self) -> Result<String, String> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            let valid = expected == Some(checksum_of(&data));
+
+            if !self.no_ack {
+                self.write_raw(if valid { b"+" } else { b"-" })?;
+            }
+
+            if valid {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let writer = self.writer.as_mut().ok_or("gdb disconnected")?;
+        writer.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+        loop {
+            self.write_raw(packet.as_bytes())?;
+
+            // Resend until gdb acknowledges the packet
+            if self.no_ack || self.read_byte()? != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    // Answers packets until gdb resumes, detaches or kills the target.
+    fn serve(&mut self, cpu: &mut CPU, memory: &mut Memory) -> Result<Resume, String> {
+        loop {
+            let packet = self.read_packet()?;
+            let (command, arguments) = packet.split_at(packet.len().min(1));
+
+            let reply = match command {
+                "?" => self.stop_reply.clone(),
+                "g" => read_registers(cpu, memory),
+                "G" => write_registers(arguments, cpu, memory).map_or_else(error, |_| "OK".to_string()),
+                "p" => read_register(arguments, cpu, memory).unwrap_or_else(|| error("Unknown register".to_string())),
+                "P" => write_register(arguments, cpu, memory).map_or_else(error, |_| "OK".to_string()),
+                "m" => read_memory(arguments, memory).unwrap_or_else(error),
+                "M" => write_memory(arguments, memory).map_or_else(error, |_| "OK".to_string()),
+                "c" | "s" => {
+                    if !arguments.is_empty() {
+                        let Some(addr) = parse_u16(arguments) else {
+                            self.send_packet(&error("Invalid address".to_string()))?;
+                            continue;
+                        };
+                        cpu.set_program_counter(addr);
+                    }
+
+                    let mode = if command == "c" { RunMode::Continue } else { RunMode::Step };
+                    return Ok(Resume::Run(mode));
+                }
+                "Z" | "z" => self.breakpoint(command == "Z", arguments, memory).unwrap_or_else(error),
+                "D" => {
+                    self.send_packet("OK")?;
+                    return Ok(Resume::Detach);
+                }
+                "k" => return Ok(Resume::Kill),
+                "H" | "T" => "OK".to_string(),
+                "Q" if packet == "QStartNoAckMode" => {
+                    // The OK is still acknowledged, later packets aren't
+                    self.send_packet("OK")?;
+                    self.no_ack = true;
+                    continue;
+                }
+                "q" => query(&packet),
+                // Everything else is unsupported, which gdb understands
+                _ => String::new(),
+            };
+
+            self.send_packet(&reply)?;
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2, Z3 and Z4 watch writes, reads and both.
+    fn breakpoint(&mut self, insert: bool, arguments: &str, memory: &mut Memory) -> Result<String, String> {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(addr), Some(length)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err("Expected type,address,kind".to_string());
+        };
+
+        let addr = parse_u16(addr).ok_or("Invalid address")?;
+        let length = parse_u16(length).ok_or("Invalid length")?.max(1);
+
+        let (read, write) = match kind {
+            "0" | "1" => {
+                let kind = if kind == "0" { BreakpointKind::Software } else { BreakpointKind::Hardware };
+
+                if insert {
+                    self.breakpoints.push((addr, kind));
+                }
+                else if let Some(index) = self.breakpoints.iter().position(|&breakpoint| breakpoint == (addr, kind)) {
+                    self.breakpoints.remove(index);
+                }
+                return Ok("OK".to_string());
+            }
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return Ok(String::new()),
+        };
+
+        let watchpoint = Watchpoint { start: addr, end: addr.saturating_add(length - 1), read, write };
+        let watchpoints = memory.watchpoints_mut();
+
+        if insert {
+            watchpoints.push(watchpoint);
+        }
+        else if let Some(index) = watchpoints.iter().position(|&existing| existing == watchpoint) {
+            watchpoints.remove(index);
+        }
+
+        Ok("OK".to_string())
+    }
+
+    // Reports why execution stopped, then hands control to gdb. Returns false
+    // if gdb kills the target.
+    fn stopped(&mut self, cpu: &mut CPU, memory: &mut Memory, report: bool) -> bool {
+        let result = if report {
+            let reply = self.stop_reply.clone();
+            self.send_packet(&reply).and_then(|_| self.serve(cpu, memory))
+        }
+        else {
+            self.serve(cpu, memory)
+        };
+
+        match result {
+            Ok(Resume::Run(mode)) => {
+                self.mode = mode;
+                self.resuming = true;
+                self.instructions = 0;
+                true
+            }
+            Ok(Resume::Kill) => false,
+            Ok(Resume::Detach) => {
+                self.disconnect(memory);
+                true
+            }
+            Err(err) => {
+                eprintln!("gdb: {}", err);
+                self.disconnect(memory);
+                true
+            }
+        }
+    }
+}
+
+impl Control for GdbStub {
+    fn before_instruction(&mut self, cpu: &mut CPU, memory: &mut Memory) -> bool {
+        if self.reader.is_none() {
+            return true;
+        }
+
+        let pc = cpu.registers().pc;
+        let resuming = self.resuming;
+        self.resuming = false;
+
+        if self.mode == RunMode::Step && !resuming {
+            self.stop(format!("S{:02x}", SIGTRAP));
+        }
+        else if let Some(&(_, kind)) = self.breakpoints.iter().find(|&&(addr, _)| addr == pc).filter(|_| !resuming && self.mode != RunMode::Stopped) {
+            let reason = if kind == BreakpointKind::Software { "swbreak" } else { "hwbreak" };
+            self.stop(format!("T{:02x}{}:;", SIGTRAP, reason));
+        }
+        else if self.mode == RunMode::Continue {
+            self.instructions = self.instructions.wrapping_add(1);
+
+            if self.instructions.is_multiple_of(INTERRUPT_POLL_INSTRUCTIONS) && self.interrupt_requested() {
+                self.stop(format!("S{:02x}", SIGINT));
+            }
+        }
+
+        if self.mode != RunMode::Stopped {
+            return true;
+        }
+
+        // Before anything has run gdb asks with '?' once it attaches
+        let report = mem::replace(&mut self.announce, false);
+        self.stopped(cpu, memory, report)
+    }
+
+    fn execute(&mut self, cpu: &mut CPU, memory: &mut Memory) {
+        if self.reader.is_none() {
+            cpu.execute_opcode(memory);
+            return;
+        }
+
+        memory.take_watch_hit();
+
+        if let Err(message) = debugger::execute_guarded(cpu, memory) {
+            // Show gdb why, then stop as if on an illegal instruction
+            let output: String = format!("{}\n", message).bytes().map(|byte| format!("{:02x}", byte)).collect();
+            let _ = self.send_packet(&format!("O{}", output));
+            self.stop(format!("S{:02x}", SIGILL));
+            self.resuming = true;
+            return;
+        }
+
+        if let Some(hit) = memory.take_watch_hit() {
+            let watchpoint = memory.watchpoints_mut().iter()
+                .find(|watchpoint| (watchpoint.start..=watchpoint.end).contains(&hit.addr))
+                .copied();
+
+            let reason = match watchpoint {
+                Some(Watchpoint { read: true, write: true, .. }) => "awatch",
+                _ if hit.write => "watch",
+                _ => "rwatch",
+            };
+            self.stop(format!("T{:02x}{}:{:x};", SIGTRAP, reason, hit.addr));
+        }
+    }
+}
+
+fn query(packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+        format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+", MAX_PACKET_SIZE)
+    }
+    else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        read_target_xml(range).unwrap_or_else(|| error("Invalid range".to_string()))
+    }
+    else if packet == "qAttached" {
+        "1".to_string()
+    }
+    else if packet == "qC" {
+        "QC1".to_string()
+    }
+    else if packet == "qfThreadInfo" {
+        "m1".to_string()
+    }
+    else if packet == "qsThreadInfo" {
+        "l".to_string()
+    }
+    else {
+        String::new()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_hex(value: &str) -> Option<usize> {
+    usize::from_str_radix(value, 16).ok()
+}
+
+// Addresses and lengths that don't fit the 16-bit address space are errors
+// rather than being cut down to fit.
+fn parse_u16(value: &str) -> Option<u16> {
+    u16::from_str_radix(value, 16).ok()
+}
+
+fn error(message: String) -> String {
+    eprintln!("gdb: {}", message);
+    "E01".to_string()
+}
+
+fn register_values(cpu: &CPU, memory: &Memory) -> [u16; REGISTER_COUNT] {
+    let registers = cpu.registers();
+    [registers.af, registers.bc, registers.de, registers.hl, memory.get_stack_pointer(), registers.pc]
+}
+
+fn set_register_values(values: [u16; REGISTER_COUNT], cpu: &mut CPU, memory: &mut Memory) {
+    let [af, bc, de, hl, sp, pc] = values;
+
+    // The low nibble of F always reads as zero
+    cpu.set_registers(af & 0xFFF0, bc, de, hl);
+    memory.set_stack_pointer(sp);
+    cpu.set_program_counter(pc);
+}
+
+fn encode_register(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn decode_register(text: &str) -> Option<u16> {
+    let bytes = decode_bytes(text)?;
+    match bytes[..] {
+        [low, high] => Some(u16::from_le_bytes([low, high])),
+        _ => None,
+    }
+}
+
+fn decode_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn read_registers(cpu: &CPU, memory: &Memory) -> String {
+    register_values(cpu, memory).iter().map(|&value| encode_register(value)).collect()
+}
+
+fn write_registers(arguments: &str, cpu: &mut CPU, memory: &mut Memory) -> Result<(), String> {
+    let mut values = [0; REGISTER_COUNT];
+
+    for (index, value) in values.iter_mut().enumerate() {
+        let text = arguments.get(index * 4..index * 4 + 4).ok_or("Too few registers")?;
+        *value = decode_register(text).ok_or("Invalid register value")?;
+    }
+
+    set_register_values(values, cpu, memory);
+    Ok(())
+}
+
+fn read_register(arguments: &str, cpu: &CPU, memory: &Memory) -> Option<String> {
+    let index = parse_hex(arguments)?;
+    register_values(cpu, memory).get(index).map(|&value| encode_register(value))
+}
+
+fn write_register(arguments: &str, cpu: &mut CPU, memory: &mut Memory) -> Result<(), String> {
+    let (index, value) = arguments.split_once('=').ok_or("Expected register=value")?;
+    let index = parse_hex(index).filter(|&index| index < REGISTER_COUNT).ok_or("Unknown register")?;
+
+    let mut values = register_values(cpu, memory);
+    values[index] = decode_register(value).ok_or("Invalid register value")?;
+    set_register_values(values, cpu, memory);
+    Ok(())
+}
+
+// Memory goes through the same path as the debugger's dump and poke, so
+// gdb looking at I/O registers doesn't set anything off.
+fn read_memory(arguments: &str, memory: &Memory) -> Result<String, String> {
+    let (addr, length) = arguments.split_once(',').ok_or("Expected address,length")?;
+    let addr = parse_u16(addr).ok_or("Invalid address")?;
+    let length = parse_hex(length).ok_or("Invalid length")?.min(MAX_PACKET_SIZE / 2);
+
+    Ok((0..length)
+        .map(|offset| format!("{:02x}", memory.read_internal(addr.wrapping_add(offset as u16) as usize)))
+        .collect())
+}
+
+fn write_memory(arguments: &str, memory: &mut Memory) -> Result<(), String> {
+    let (location, data) = arguments.split_once(':').ok_or("Expected address,length:data")?;
+    let (addr, length) = location.split_once(',').ok_or("Expected address,length")?;
+    let addr = parse_u16(addr).ok_or("Invalid address")?;
+    let bytes = decode_bytes(data).ok_or("Invalid data")?;
+
+    if parse_hex(length) != Some(bytes.len()) {
+        return Err("Data doesn't match its length".to_string());
+    }
+
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        memory.write_internal(addr.wrapping_add(offset as u16) as usize, byte);
+    }
+
+    Ok(())
+}
+
+fn read_target_xml(range: &str) -> Option<String> {
+    let (offset, length) = range.split_once(',')?;
+    let offset = parse_hex(offset)?;
+    let length = parse_hex(length)?;
+
+    let document = TARGET_XML.as_bytes();
+    let start = offset.min(document.len());
+    let end = start.saturating_add(length).min(document.len());
+    let marker = if end < document.len() { "m" } else { "l" };
+
+    Some(format!("{}{}", marker, String::from_utf8_lossy(&document[start..end])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stub talking to the returned end of a local connection
+    fn connected() -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        (GdbStub::new(stream).unwrap(), client)
+    }
+
+    fn receive(client: &mut TcpStream, length: usize) -> String {
+        let mut data = vec![0; length];
+        client.read_exact(&mut data).unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn packets_with_a_bad_checksum_are_refused() {
+        let (mut stub, mut client) = connected();
+        client.write_all(b"+$m0,2#00$m0,2#fb").unwrap();
+
+        assert_eq!(stub.read_packet().unwrap(), "m0,2");
+        assert_eq!(receive(&mut client, 2), "-+");
+    }
+
+    #[test]
+    fn replies_are_resent_until_acknowledged() {
+        let (mut stub, mut client) = connected();
+        client.write_all(b"-+").unwrap();
+
+        stub.send_packet("OK").unwrap();
+        assert_eq!(receive(&mut client, 12), "$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn memory_reads_and_writes() {
+        let mut memory = Memory::new();
+
+        assert!(write_memory("c000,2:1234", &mut memory).is_ok());
+        assert_eq!(read_memory("c000,3", &memory).unwrap(), "123400");
+
+        // Addresses wrap around the top of memory
+        assert!(write_memory("ffff,2:5678", &mut memory).is_ok());
+        assert_eq!(memory.read_internal(0x0000), 0x78);
+        assert_eq!(read_memory("ffff,1", &memory).unwrap(), "56");
+    }
+
+    #[test]
+    fn bad_memory_requests_are_errors() {
+        let mut memory = Memory::new();
+
+        assert!(read_memory("ffffffffffffffff,2", &memory).is_err());
+        assert!(read_memory("10000,2", &memory).is_err());
+        assert!(write_memory("c000,3:1234", &mut memory).is_err());
+        assert!(write_memory("c000,1:1234", &mut memory).is_err());
+        assert_eq!(memory.read_internal(0xC000), 0);
+    }
+
+    #[test]
+    fn watchpoints_are_added_and_removed() {
+        let (mut stub, _client) = connected();
+        let mut memory = Memory::new();
+
+        assert_eq!(stub.breakpoint(true, "3,c000,2", &mut memory).unwrap(), "OK");
+        assert!(memory.watchpoints_mut()[..] == [Watchpoint { start: 0xC000, end: 0xC001, read: true, write: false }]);
+
+        assert_eq!(stub.breakpoint(false, "3,c000,2", &mut memory).unwrap(), "OK");
+        assert!(memory.watchpoints_mut().is_empty());
+
+        assert!(stub.breakpoint(true, "2,ffffffffffffffff,1", &mut memory).is_err());
+        assert!(stub.breakpoint(true, "4,c000,10000", &mut memory).is_err());
+    }
+}
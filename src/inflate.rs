@@ -0,0 +1,275 @@
+// A DEFLATE decoder for the compressed ROMs in zip and gzip archives.
+
+const MAX_BITS: usize = 15;
+const END_OF_BLOCK: u16 = 256;
+const LITERAL_CODES: usize = 288;
+const DISTANCE_CODES: usize = 30;
+
+const STORED_BLOCK: u32 = 0;
+const FIXED_BLOCK: u32 = 1;
+const DYNAMIC_BLOCK: u32 = 2;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// The order code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0, buffer: 0, count: 0 }
+    }
+
+    // Bits are packed starting from the least significant bit of each byte.
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or("Compressed data ends early")?;
+            self.buffer |= (byte as u32) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.position..self.position + count).ok_or("Compressed data ends early")?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+// A canonical Huffman code, stored as the number of codes of each length
+// and the symbols in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length] as usize;
+        }
+
+        let mut symbols = vec![0; offsets[MAX_BITS + 1]];
+        for (symbol, &length) in lengths.iter().enumerate().filter(|(_, &length)| length != 0) {
+            symbols[offsets[length as usize]] = symbol as u16;
+            offsets[length as usize] += 1;
+        }
+
+        Huffman { counts, symbols }
+    }
+
+    // Codes are read a bit at a time from the most significant end, each
+    // length's codes following on from the last code of the length before.
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+
+        for length in 1..=MAX_BITS {
+            code |= reader.bits(1)? as usize;
+            let count = self.counts[length] as usize;
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("Invalid Huffman code in compressed data".to_string())
+    }
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? == 1;
+
+        match reader.bits(2)? {
+            STORED_BLOCK => {
+                reader.align();
+                let header = reader.bytes(4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err("Stored block length doesn't match its complement".to_string());
+                }
+                output.extend_from_slice(reader.bytes(length as usize)?);
+            }
+            FIXED_BLOCK => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            DYNAMIC_BLOCK => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err("Invalid block type in compressed data".to_string()),
+        }
+
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    (Huffman::new(&lengths), Huffman::new(&[5; DISTANCE_CODES]))
+}
+
+// The code lengths for the block are themselves Huffman coded, with
+// symbols 16 to 18 repeating the previous length or zero.
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; CODE_LENGTH_ORDER.len()];
+    for &index in &CODE_LENGTH_ORDER[..length_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("Repeated code length with nothing before it")?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            _ => return Err("Invalid code length in compressed data".to_string()),
+        };
+        lengths.extend((0..repeat).map(|_| length));
+    }
+
+    if lengths.len() > literal_count + distance_count {
+        return Err("Code lengths overrun the block header".to_string());
+    }
+
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)?;
+
+        if symbol < END_OF_BLOCK {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let index = (symbol - END_OF_BLOCK - 1) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err("Invalid length code in compressed data".to_string());
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err("Invalid distance code in compressed data".to_string());
+        }
+        let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+
+        if distance > output.len() {
+            return Err("Compressed data refers back before its start".to_string());
+        }
+
+        // The copy can overlap what it's writing, so it goes a byte at a time
+        let start = output.len() - distance;
+        for offset in 0..length {
+            output.push(output[start + offset]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_block() {
+        let data = [0x01, 0x06, 0x00, 0xF9, 0xFF, b's', b't', b'o', b'r', b'e', b'd'];
+        assert_eq!(inflate(&data).unwrap(), b"stored");
+    }
+
+    #[test]
+    fn fixed_codes_with_back_references() {
+        let data = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0xB9, 0x00];
+        assert_eq!(inflate(&data).unwrap(), b"hello hello hello hello\n");
+    }
+
+    #[test]
+    fn dynamic_codes() {
+        let data = [
+            0x85, 0xCB, 0x3B, 0x0A, 0x80, 0x30, 0x10, 0x04, 0xD0, 0x3E, 0xA7, 0xD8, 0x03, 0x48, 0xF0, 0xAF,
+            0x39, 0x8E, 0x81, 0x15, 0x8B, 0x60, 0x40, 0x03, 0x5E, 0xDF, 0xE9, 0xA7, 0x98, 0xFA, 0xF1, 0x52,
+            0xB2, 0x5C, 0x5B, 0x2B, 0xFE, 0x5A, 0x3D, 0x2D, 0xBB, 0x3F, 0x56, 0x6F, 0x6B, 0x97, 0xDB, 0x77,
+            0x94, 0xD2, 0x59, 0x22, 0x8F, 0x21, 0xED, 0xE2, 0x90, 0xE3, 0x6C, 0xE2, 0x90, 0xE3, 0xAC, 0xE2,
+            0x90, 0xE3, 0x2C, 0xE2, 0x90, 0xE3, 0xCC, 0xE2, 0x90, 0xE3, 0x4C, 0xE2, 0x90, 0xE3, 0x8C, 0xE2,
+            0x90, 0xE3, 0x0C, 0xE2, 0x90, 0xE3, 0xF4, 0xE2, 0x90, 0xC7, 0xF0, 0x03,
+        ];
+        let expected: String = (90..=99).rev()
+            .map(|count| format!("{} bottles of beer on the wall, {} bottles of beer.\n", count, count))
+            .collect();
+
+        assert_eq!(inflate(&data).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn damaged_data() {
+        // Cut short, a reserved block type, and a stored length that doesn't match its complement
+        assert!(inflate(&[0xCB, 0x48, 0xCD, 0xC9]).is_err());
+        assert!(inflate(&[0x07]).is_err());
+        assert!(inflate(&[0x01, 0x06, 0x00, 0xF8, 0xFF]).is_err());
+    }
+}
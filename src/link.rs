@@ -0,0 +1,165 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::serial::{PortState, SerialDevice, TRANSFER_CYCLES};
+
+// Both sides stop at every quantum boundary and swap a sync packet, so
+// neither can run more than one quantum ahead of the other. A quantum is
+// one byte transfer long, which guarantees a transfer started with our own
+// clock is seen at a boundary before it completes.
+const QUANTUM_CYCLES: u64 = TRANSFER_CYCLES as u64;
+
+const PACKET_SIZE: usize = 10;
+const FLAG_INTERNAL: u8 = 0x01;
+const FLAG_EXTERNAL: u8 = 0x02;
+
+struct SyncPacket {
+    quantum: u64,
+    flags: u8,
+    data: u8,
+}
+
+impl SyncPacket {
+    fn to_bytes(&self) -> [u8; PACKET_SIZE] {
+        let mut bytes = [0; PACKET_SIZE];
+        bytes[0..8].copy_from_slice(&self.quantum.to_le_bytes());
+        bytes[8] = self.flags;
+        bytes[9] = self.data;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; PACKET_SIZE]) -> Self {
+        let mut quantum = [0; 8];
+        quantum.copy_from_slice(&bytes[0..8]);
+
+        SyncPacket {
+            quantum: u64::from_le_bytes(quantum),
+            flags: bytes[8],
+            data: bytes[9],
+        }
+    }
+}
+
+pub struct LinkCable {
+    stream: Option<TcpStream>,
+    cycles: u64,
+    quantum: u64,
+    // Byte received for the internal clock transfer reported at the last boundary
+    incoming: Option<u8>,
+    transfer_reported: bool,
+}
+
+impl LinkCable {
+    pub fn host(port: u16) -> Result<LinkCable, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        println!("Waiting for link cable connection on port {}", port);
+
+        let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        LinkCable::new(stream)
+    }
+
+    pub fn connect(port: u16) -> Result<LinkCable, String> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        LinkCable::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<LinkCable, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+
+        Ok(LinkCable {
+            stream: Some(stream),
+            cycles: 0,
+            quantum: 0,
+            incoming: None,
+            transfer_reported: false,
+        })
+    }
+
+    fn swap_packets(&mut self, packet: &SyncPacket) -> Result<SyncPacket, String> {
+        let stream = self.stream.as_mut().expect("link cable swap while disconnected");
+
+        stream.write_all(&packet.to_bytes()).map_err(|e| e.to_string())?;
+
+        let mut bytes = [0; PACKET_SIZE];
+        stream.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+
+        let remote = SyncPacket::from_bytes(&bytes);
+        if remote.quantum != packet.quantum {
+            return Err(format!("Link cable out of sync, quantum {} against {}", remote.quantum, packet.quantum));
+        }
+
+        Ok(remote)
+    }
+
+    fn sync_boundary(&mut self, port: &PortState) -> Option<u8> {
+        let mut flags = 0;
+
+        if !port.internal_transfer {
+            // Any transfer we reported earlier was cancelled
+            self.transfer_reported = false;
+            self.incoming = None;
+        }
+
+        if port.internal_transfer && !self.transfer_reported {
+            flags |= FLAG_INTERNAL;
+            self.transfer_reported = true;
+        }
+        if port.external_transfer {
+            flags |= FLAG_EXTERNAL;
+        }
+
+        let packet = SyncPacket {
+            quantum: self.quantum,
+            flags,
+            data: port.data,
+        };
+
+        let remote = match self.swap_packets(&packet) {
+            Ok(remote) => remote,
+            Err(err) => {
+                // Carry on as if the cable had been pulled out
+                eprintln!("Link cable: {}", err);
+                self.stream = None;
+                return None;
+            }
+        };
+
+        if flags & FLAG_INTERNAL != 0 && remote.flags & FLAG_EXTERNAL != 0 {
+            self.incoming = Some(remote.data);
+        }
+
+        if flags & FLAG_EXTERNAL != 0 && remote.flags & FLAG_INTERNAL != 0 {
+            return Some(remote.data);
+        }
+
+        None
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        self.transfer_reported = false;
+
+        // The other side wasn't listening when we started clocking
+        self.incoming.take().unwrap_or(0xFF)
+    }
+
+    fn sync(&mut self, ticks: u32, port: &PortState) -> Option<u8> {
+        self.stream.as_ref()?;
+
+        self.cycles += ticks as u64;
+
+        while self.cycles >= (self.quantum + 1) * QUANTUM_CYCLES {
+            let incoming = self.sync_boundary(port);
+            self.quantum += 1;
+
+            // The port state is stale once a transfer completes or the cable
+            // drops, any remaining boundaries are handled on the next call
+            if incoming.is_some() || self.stream.is_none() {
+                return incoming;
+            }
+        }
+
+        None
+    }
+}
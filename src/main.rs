@@ -1,26 +1,427 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process;
-use std::time::Duration;
 
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{KeyboardState, Keycode, Mod, Scancode};
+
+mod archive;
+mod cheats;
+mod checksum;
+mod compatibility;
 mod cpu;
+mod debugger;
+mod disassembler;
+mod gdb;
+mod inflate;
 mod ppu;
+mod link;
 mod memory;
+mod memory_viewer;
+mod model;
+mod movie;
+mod movie_import;
+mod options;
+mod palette;
+mod patch;
+mod png;
+mod printer;
+mod rewind;
+mod search;
+mod serial;
+mod sgb;
+mod speed;
+mod state;
+mod symbols;
+mod trace;
+mod viewer;
+
+// The header runs from 0x100 to the global checksum at 0x14E
+const CARTRIDGE_HEADER_END: usize = 0x150;
 
 fn main() {
 
+    let mut args = env::args().skip(1).peekable();
+
+    if args.peek().is_some_and(|command| command == "disasm") {
+        args.next();
+
+        if let Err(err) = disassemble(args) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let options = options::Options::parse(args).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    });
+
     let mut memory = memory::Memory::new();
 
-    memory.load_rom().unwrap_or_else(|err| {
+    memory.load_rom(&options.rom_path, options.zip_entry.as_deref(), options.patch.as_deref()).unwrap_or_else(|err| {
         eprintln!("Error: {}", err);
         process::exit(1);
     });
+    memory.set_cheats(cheats::Cheats::next_to(memory.rom_path().unwrap_or(&options.rom_path)));
+
+    if let Some(boot_rom) = options.boot_rom.as_ref() {
+        memory.load_boot_rom(boot_rom).unwrap_or_else(|err| {
+            eprintln!("Error: boot ROM: {}", err);
+            process::exit(1);
+        });
+    }
+
+    if let Some(target) = options.serial_out.as_ref() {
+        let capture: Box<dyn Write> = if target == "stdout" {
+            Box::new(io::stdout())
+        }
+        else {
+            Box::new(File::create(target).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }))
+        };
+        memory.serial_mut().set_capture(capture);
+    }
+
+    if let Some(link) = options.link.as_ref() {
+        let cable = match link {
+            options::LinkMode::Host(port) => link::LinkCable::host(*port),
+            options::LinkMode::Client(port) => link::LinkCable::connect(*port),
+        };
+
+        let cable = cable.unwrap_or_else(|err| {
+            eprintln!("Error: link cable: {}", err);
+            process::exit(1);
+        });
+        memory.serial_mut().set_device(Box::new(cable));
+    }
+
+    if let Some(output_dir) = options.printer.as_ref() {
+        let printer = printer::Printer::new(PathBuf::from(output_dir));
+        memory.serial_mut().set_device(Box::new(printer));
+    }
+
+    // Without a choice, games get the hardware they were made for
+    let model = options.model.unwrap_or(if memory.supports_cgb() { model::Model::Cgb } else { model::Model::Dmg });
+    memory.set_model(model);
+
+    let mut tracer = options.trace.as_ref().map(|path| {
+        trace::Tracer::new(path, options.trace_filter.clone(), options.symbols.clone()).unwrap_or_else(|err| {
+            eprintln!("Error: trace: {}", err);
+            process::exit(1);
+        })
+    });
+
+    let mut debugger: Option<Box<dyn debugger::Control>> = if let Some(port) = options.gdb {
+        let stub = gdb::GdbStub::listen(port).unwrap_or_else(|err| {
+            eprintln!("Error: gdb: {}", err);
+            process::exit(1);
+        });
+        Some(Box::new(stub))
+    }
+    else if options.debug {
+        Some(Box::new(debugger::Debugger::new(options.symbols.clone())))
+    }
+    else {
+        None
+    };
+
+    let mut ram_search = options.ram_search.then(search::RamSearch::new);
 
     let mut cpu = cpu::CPU::new();
-    let mut ppu = ppu::PPU::new();
 
+    // Anything too small for a cartridge header, like the default boot ROM
+    // image, runs from the start as it is
+    if !memory.has_boot_rom() && memory.cartridge().len() >= CARTRIDGE_HEADER_END {
+        skip_boot_rom(&mut cpu, &mut memory, &options);
+    }
+
+    if options.headless {
+        let mut ppu = ppu::PPU::new(None, memory.sgb().is_some());
+        let mut movie = start_movie(&options, &mut cpu, &mut memory, &mut ppu);
+        // Without a frame count a movie plays to its end
+        let play_to_end = options.frames.is_none() && options.play.is_some();
+        let mut frame = 0;
+
+        while options.frames.is_none_or(|frames| frame < frames) {
+            if let Some(movie) = movie.as_mut() {
+                memory.set_buttons(movie.next_frame(0));
+                if play_to_end && !movie.is_playing() {
+                    break;
+                }
+            }
+
+            if !run_frame(&mut cpu, &mut memory, &mut ppu, tracer.as_mut(), debugger.as_deref_mut()) {
+                break;
+            }
+            memory.apply_cheats();
+            if let Some(search) = ram_search.as_mut() {
+                search.update(&mut memory, !movie_active(&movie));
+            }
+            frame += 1;
+        }
+
+        finish_movie(movie);
+        return;
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+
+    let mut ppu = ppu::PPU::new(Some(&sdl_context), memory.sgb().is_some());
+    let mut viewers = viewer::Viewers::new(&sdl_context, &options, memory.is_cgb());
+    let mut limiter = speed::FrameLimiter::new(options.fast_forward);
+    let mut rewind = rewind::RewindBuffer::new(options.rewind_interval, options.rewind_buffer_size);
+    let mut rewinding = false;
+    let mut movie = start_movie(&options, &mut cpu, &mut memory, &mut ppu);
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            if viewers.handle_event(&event, &mut memory, !movie_active(&movie)) {
+                continue;
+            }
+
+            match event {
+                Event::Quit {..} |
+                Event::Window { win_event: WindowEvent::Close, .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } => limiter.set_fast_forward(true),
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => limiter.set_fast_forward(false),
+                // Going back would leave the movie out of step with the game
+                Event::KeyDown { keycode: Some(Keycode::Backspace), repeat: false, .. } => rewinding = !movie_active(&movie),
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
+                Event::KeyDown { keycode: Some(Keycode::L), repeat: false, .. } => limiter.toggle_slow_motion(),
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => limiter.toggle_pause(),
+                Event::KeyDown { keycode: Some(Keycode::N), .. } => limiter.advance_frame(),
+                Event::KeyDown { keycode: Some(Keycode::M), repeat: false, .. } => {
+                    if let Some(movie) = movie.as_mut().filter(|movie| movie.is_playing()) {
+                        let read_only = movie.toggle_read_only();
+                        println!("Movie: {}", if read_only { "read-only" } else { "read-write" });
+                    }
+                }
+                // Cheats aren't recorded, so they stay as they are during a movie
+                Event::KeyDown { keycode: Some(Keycode::C), repeat: false, .. } if !movie_active(&movie) => {
+                    let enabled = memory.cheats_mut().toggle_all();
+                    println!("Cheats {}", if enabled { "on" } else { "off" });
+                }
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } => {
+                    if let Some(index) = cheat_index(keycode) {
+                        if movie_active(&movie) {
+                            continue;
+                        }
+                        if let Some(cheat) = memory.cheats_mut().toggle(index) {
+                            println!("Cheat {}: {}", cheat.name, if cheat.enabled { "on" } else { "off" });
+                        }
+                    }
+                    else if let Some(slot) = state_slot(keycode) {
+                        let path = state::slot_path(memory.rom_path().unwrap_or(&options.rom_path), memory.rom_checksum(), slot);
+
+                        let result = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            state::save_slot(&path, &cpu, &memory, &ppu)
+                        }
+                        else if movie_active(&movie) {
+                            Err("can't be loaded while a movie is running".to_string())
+                        }
+                        else {
+                            state::load_slot(&path, &mut cpu, &mut memory, &mut ppu).map(|()| ppu.render(&mut memory))
+                        };
+
+                        if let Err(err) = result {
+                            eprintln!("Save state slot {}: {}", slot, err);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !limiter.should_run_frame() {
+            continue;
+        }
+
+        if rewinding {
+            if let Err(err) = rewind.step_back(&mut cpu, &mut memory, &mut ppu) {
+                eprintln!("Rewind: {}", err);
+            }
+            ppu.render(&mut memory);
+        }
+        else {
+            let held = joypad_buttons(&event_pump.keyboard_state());
+            match movie.as_mut() {
+                Some(movie) => {
+                    memory.set_buttons(movie.next_frame(held));
+                    ppu.set_title(&format!("dustboy - {}", movie.status()));
+                }
+                None => memory.set_buttons(held),
+            }
+
+            if !run_frame(&mut cpu, &mut memory, &mut ppu, tracer.as_mut(), debugger.as_deref_mut()) {
+                break 'running;
+            }
+            memory.apply_cheats();
+            if let Some(search) = ram_search.as_mut() {
+                search.update(&mut memory, !movie_active(&movie));
+            }
+            rewind.capture(&cpu, &memory, &ppu);
+        }
+
+        ppu.display();
+        viewers.update(&memory);
+
+        limiter.wait();
+    }
+
+    finish_movie(movie);
+}
+
+fn start_movie(options: &options::Options, cpu: &mut cpu::CPU, memory: &mut memory::Memory, ppu: &mut ppu::PPU) -> Option<movie::MovieSession> {
+    let session = if let Some(path) = options.play.as_ref() {
+        movie::MovieSession::play(path, !options.read_write, memory)
+    }
+    else if let Some(path) = options.record.as_ref() {
+        let start = match options.movie_state.as_ref() {
+            Some(state_path) => fs::read(state_path)
+                .map(movie::Start::State)
+                .map_err(|err| format!("{}: {}", state_path, err)),
+            None => Ok(movie::Start::PowerOn),
+        };
+        start.map(|start| movie::MovieSession::record(path, movie::Movie::new(memory, start)))
+    }
+    else {
+        return None;
+    };
+
+    let session = session.and_then(|session| session.begin(cpu, memory, ppu).map(|_| session));
+    Some(session.unwrap_or_else(|err| {
+        eprintln!("Error: movie: {}", err);
+        process::exit(1);
+    }))
+}
+
+fn finish_movie(movie: Option<movie::MovieSession>) {
+    if let Err(err) = movie.map_or(Ok(()), |movie| movie.finish()) {
+        eprintln!("Error: movie: {}", err);
+    }
+}
+
+fn movie_active(movie: &Option<movie::MovieSession>) -> bool {
+    movie.as_ref().is_some_and(|movie| movie.is_active())
+}
+
+fn joypad_buttons(keyboard: &KeyboardState) -> u8 {
+    [
+        (Scancode::Right, memory::JOYPAD_RIGHT),
+        (Scancode::Left, memory::JOYPAD_LEFT),
+        (Scancode::Up, memory::JOYPAD_UP),
+        (Scancode::Down, memory::JOYPAD_DOWN),
+        (Scancode::X, memory::JOYPAD_A),
+        (Scancode::Z, memory::JOYPAD_B),
+        (Scancode::RShift, memory::JOYPAD_SELECT),
+        (Scancode::Return, memory::JOYPAD_START),
+    ]
+    .iter()
+    .filter(|(scancode, _)| keyboard.is_scancode_pressed(*scancode))
+    .fold(0, |buttons, (_, button)| buttons | button)
+}
+
+fn disassemble<I: Iterator<Item = String>>(args: I) -> Result<(), String> {
+    let options = options::DisasmOptions::parse(args)?;
+    let rom = archive::read_rom(&options.rom_path, options.zip_entry.as_deref())?.data;
+
+    let from = options.from.unwrap_or(if options.bank == 0 { 0x0000 } else { 0x4000 });
+    disassembler::print_listing(&rom, options.bank, from, options.count, &options.symbols)
+}
+
+// Leaves the machine as the boot ROM would when handing over to the cartridge.
+fn skip_boot_rom(cpu: &mut cpu::CPU, memory: &mut memory::Memory, options: &options::Options) {
+    let model = memory.model();
+
+    let [af, bc, de, hl] = model.post_boot_registers(memory.cartridge(), memory.is_cgb());
+    cpu.set_registers(af, bc, de, hl);
+    cpu.set_program_counter(0x0100);
+    memory.set_stack_pointer(0xFFFE);
+
+    for (addr, value) in model.io_defaults() {
+        memory.write_internal(addr, value);
+    }
+
+    if model.is_cgb() {
+        // The CGB boot ROM leaves SC reading 0x7F
+        memory.serial_mut().write_control(0x7F);
+
+        if !memory.is_cgb() {
+            let combination = options.cgb_palette.unwrap_or_else(|| compatibility::lookup_combination(memory.cartridge()));
+            memory.set_compatibility_palettes(compatibility::combination_palettes(combination));
+        }
+    }
+}
+
+fn cheat_index(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        Keycode::Num7 => Some(6),
+        Keycode::Num8 => Some(7),
+        Keycode::Num9 => Some(8),
+        _ => None
+    }
+}
+
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        Keycode::F9 => Some(9),
+        _ => None
+    }
+}
+
+// Returns false when the debugger asks to quit.
+fn run_frame(
+    cpu: &mut cpu::CPU,
+    memory: &mut memory::Memory,
+    ppu: &mut ppu::PPU,
+    mut tracer: Option<&mut trace::Tracer>,
+    mut debugger: Option<&mut (dyn debugger::Control + 'static)>
+) -> bool {
     loop {
-        cpu.execute_opcode(&mut memory);
-        ppu.render(&mut memory);
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.log(cpu, memory);
+        }
+
+        match debugger.as_mut() {
+            Some(debugger) => {
+                if !debugger.before_instruction(cpu, memory) {
+                    return false;
+                }
+                debugger.execute(cpu, memory);
+            }
+            None => cpu.execute_opcode(memory),
+        }
+        let ticks = cpu.get_ticks() + memory.take_stall_cycles();
+
+        memory.step(ticks);
+
+        // Double speed only applies to the CPU side, the PPU keeps its rate
+        let ppu_ticks = if memory.is_double_speed() { ticks / 2 } else { ticks };
 
-        //::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 2));
+        if ppu.step(ppu_ticks, memory) {
+            return true;
+        }
     }
-} 
\ No newline at end of file
+}
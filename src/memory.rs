@@ -1,11 +1,237 @@
+use std::cell::Cell;
 use std::fs::File;
 use std::error::Error;
 use std::io::prelude::*;
+use std::path::PathBuf;
+
+use crate::archive;
+use crate::cheats::Cheats;
+use crate::checksum::crc32;
+use crate::model::Model;
+use crate::patch;
+use crate::palette::ColourPalettes;
+use crate::serial::{self, Serial};
+use crate::sgb::{self, Sgb};
+use crate::state::{StateReader, StateWriter};
+
+const INTERRUPT_FLAG: usize = 0xFF0F;
+const LCD_STAT_INTERRUPT: u8 = 0x02;
+const SERIAL_INTERRUPT: u8 = 0x08;
+const JOYPAD_INTERRUPT: u8 = 0x10;
+
+const P1: usize = 0xFF00;
+const P1_SELECT: u8 = 0x30;
+const P1_DIRECTIONS: u8 = 0x10;
+const P1_BUTTONS: u8 = 0x20;
+
+// Pressed buttons, directions in the low nibble and the rest in the high
+// nibble in the order P1 reports them
+pub const JOYPAD_RIGHT: u8 = 0x01;
+pub const JOYPAD_LEFT: u8 = 0x02;
+pub const JOYPAD_UP: u8 = 0x04;
+pub const JOYPAD_DOWN: u8 = 0x08;
+pub const JOYPAD_A: u8 = 0x10;
+pub const JOYPAD_B: u8 = 0x20;
+pub const JOYPAD_SELECT: u8 = 0x40;
+pub const JOYPAD_START: u8 = 0x80;
+
+const LCDC: usize = 0xFF40;
+const STAT: usize = 0xFF41;
+const LY: usize = 0xFF44;
+const LYC: usize = 0xFF45;
+// Mode and coincidence flag are set by the PPU
+const STAT_READ_ONLY: u8 = 0x07;
+
+const DMA: usize = 0xFF46;
+const OAM_START: usize = 0xFE00;
+const OAM_SIZE: u16 = 0xA0;
+const OAM_END: usize = 0xFEFF;
+// OAM is scanned 8 bytes at a time
+const OAM_ROW_SIZE: usize = 8;
+const DMA_CYCLES_PER_BYTE: u32 = 4;
+
+const HRAM_START: usize = 0xFF80;
+const HRAM_END: usize = 0xFFFE;
+
+// Writing a non-zero value unmaps the boot ROM until the next reset
+const BOOT_ROM_DISABLE: usize = 0xFF50;
+// The CGB boot ROM leaves a gap for the cartridge header
+const BOOT_ROM_HEADER_START: usize = 0x0100;
+const BOOT_ROM_HEADER_END: usize = 0x01FF;
+
+const CGB_FLAG: usize = 0x0143;
+const VBK: usize = 0xFF4F;
+const SVBK: usize = 0xFF70;
+const BCPS: usize = 0xFF68;
+const BCPD: usize = 0xFF69;
+const OCPS: usize = 0xFF6A;
+const OCPD: usize = 0xFF6B;
+
+const KEY1: usize = 0xFF4D;
+const HDMA1: usize = 0xFF51;
+const HDMA2: usize = 0xFF52;
+const HDMA3: usize = 0xFF53;
+const HDMA4: usize = 0xFF54;
+const HDMA5: usize = 0xFF55;
+
+const SPEED_SWITCH_PREPARE: u8 = 0x01;
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+// Each 16 byte block stalls the CPU for 8 M-cycles at single speed, the
+// transfer takes the same time in double speed so twice as many cycles.
+const HDMA_BLOCK_STALL: u32 = 32;
+
+const ROM_SIZE: usize = 0x8000;
+const VRAM_START: usize = 0x8000;
+const VRAM_BANK_SIZE: usize = 0x2000;
+const VRAM_BANKS: usize = 2;
+const WRAM_START: usize = 0xC000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+const ECHO_START: usize = 0xE000;
+const ECHO_END: usize = 0xFDFF;
+
+// Stops the debugger when the CPU accesses any address in the range
+#[derive(Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub write: bool,
+    pub value: u8,
+}
+
+enum Banked {
+    Vram(usize),
+    Wram(usize),
+}
+
+struct VramDma {
+    source: u16,
+    destination: u16,
+    remaining_blocks: u8,
+    hblank_active: bool,
+}
+
+impl VramDma {
+    fn new() -> Self {
+        VramDma {
+            source: 0,
+            destination: 0,
+            remaining_blocks: 0,
+            hblank_active: false,
+        }
+    }
+
+    // Bit 7 clear while an HBlank transfer is running, the lower bits hold
+    // the remaining number of blocks minus one. Reads 0xFF when finished.
+    fn read_control(&self) -> u8 {
+        let length = self.remaining_blocks.wrapping_sub(1) & 0x7F;
+
+        if self.hblank_active { length } else { 0x80 | length }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.remaining_blocks);
+        writer.write_bool(self.hblank_active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.source = reader.read_u16()?;
+        self.destination = reader.read_u16()?;
+        self.remaining_blocks = reader.read_u8()?;
+        self.hblank_active = reader.read_bool()?;
+
+        Ok(())
+    }
+}
+
+struct OamDma {
+    active: bool,
+    source: u16,
+    index: u16,
+    cycles: u32,
+    // Last byte put on the bus by the transfer, what the CPU reads meanwhile
+    current_byte: u8,
+}
+
+impl OamDma {
+    fn new() -> Self {
+        OamDma {
+            active: false,
+            source: 0,
+            index: 0,
+            cycles: 0,
+            current_byte: 0xFF,
+        }
+    }
+
+    fn start(&mut self, page: u8) {
+        self.active = true;
+        self.source = (page as u16) << 8;
+        self.index = 0;
+        self.cycles = 0;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.active);
+        writer.write_u16(self.source);
+        writer.write_u16(self.index);
+        writer.write_u32(self.cycles);
+        writer.write_u8(self.current_byte);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.active = reader.read_bool()?;
+        self.source = reader.read_u16()?;
+        self.index = reader.read_u16()?;
+        self.cycles = reader.read_u32()?;
+        self.current_byte = reader.read_u8()?;
+
+        Ok(())
+    }
+}
 
 pub struct Memory {
     ram: [u8; 0x10000],
     cartridge: Vec<u8>,
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
+    rom_checksum: u32,
+    // Where the ROM would be outside of its archive
+    rom_path: Option<String>,
     stack_pointer:  u16,
+    serial: Serial,
+    oam_dma: OamDma,
+    // Row of OAM being read while the PPU scans it in mode 2
+    oam_scan_row: Option<usize>,
+    model: Model,
+    sgb: Option<Sgb>,
+    cgb_mode: bool,
+    // A monochrome game on a CGB, coloured through the palettes set at boot
+    compatibility_palettes: bool,
+    vram: Vec<u8>,
+    vram_bank: usize,
+    wram: Vec<u8>,
+    wram_bank: usize,
+    bg_palettes: ColourPalettes,
+    obj_palettes: ColourPalettes,
+    double_speed: bool,
+    speed_switch_prepared: bool,
+    vram_dma: VramDma,
+    stall_cycles: u32,
+    buttons: u8,
+    watchpoints: Vec<Watchpoint>,
+    cheats: Cheats,
+    // Reads don't otherwise change memory, so the hit is kept in a Cell
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Memory {
@@ -13,29 +239,607 @@ impl Memory {
         Memory {
             ram: [0; 0x10000],
             cartridge: Vec::new(),
+            boot_rom: Vec::new(),
+            boot_rom_mapped: false,
+            rom_checksum: 0,
+            rom_path: None,
             stack_pointer: 0,
+            serial: Serial::new(),
+            oam_dma: OamDma::new(),
+            oam_scan_row: None,
+            model: Model::Dmg,
+            sgb: None,
+            cgb_mode: false,
+            compatibility_palettes: false,
+            vram: vec![0; VRAM_BANK_SIZE * VRAM_BANKS],
+            vram_bank: 0,
+            wram: vec![0; WRAM_BANK_SIZE * WRAM_BANKS],
+            wram_bank: 1,
+            bg_palettes: ColourPalettes::new(),
+            obj_palettes: ColourPalettes::new(),
+            double_speed: false,
+            speed_switch_prepared: false,
+            vram_dma: VramDma::new(),
+            stall_cycles: 0,
+            buttons: 0,
+            watchpoints: Vec::new(),
+            cheats: Cheats::default(),
+            watch_hit: Cell::new(None),
         }
     }
 
-    pub fn load_rom(&mut self) -> Result<(), String> {
+    pub fn load_rom(&mut self, rom_path: &str, zip_entry: Option<&str>, patch_path: Option<&str>) -> Result<(), String> {
 
-        let rom_path = "roms/boot-rom.gb";
+        let rom = archive::read_rom(rom_path, zip_entry)?;
 
-        let mut rom = File::open(rom_path).map_err(|e| e.description().to_string())?;
-        rom.read_to_end(&mut self.cartridge).map_err(|e| e.description().to_string())?;
+        // Patches are applied to the copy in memory, the ROM file is left as it is
+        self.cartridge = match patch_path.map(PathBuf::from).or_else(|| patch::next_to(&rom.path)) {
+            Some(patch_path) => patch::load(&rom.data, &patch_path)?,
+            None => rom.data,
+        };
+        self.rom_path = Some(rom.path);
 
-       for (i, byte) in self.cartridge.bytes().enumerate() {
+       // Without bank switching only the first 32KB can be mapped
+       for (i, byte) in self.cartridge.bytes().take(ROM_SIZE).enumerate() {
             self.ram[i] =  byte.map_err(|e| e.description().to_string())?;
         }
+
+
+        self.rom_checksum = crc32(&self.cartridge);
+        Ok(())
+    }
+
+    pub fn load_boot_rom(&mut self, boot_rom_path: &str) -> Result<(), String> {
+        let mut boot_rom = File::open(boot_rom_path).map_err(|e| e.to_string())?;
+        boot_rom.read_to_end(&mut self.boot_rom).map_err(|e| e.to_string())?;
+
+        self.boot_rom_mapped = true;
+        Ok(())
+    }
+
+    pub fn has_boot_rom(&self) -> bool {
+        !self.boot_rom.is_empty()
+    }
+
+    pub fn cartridge(&self) -> &[u8] {
+        &self.cartridge
+    }
+
+    // 0x80 marks a game with CGB features, 0xC0 one which is CGB only
+    pub fn supports_cgb(&self) -> bool {
+        self.cartridge.get(CGB_FLAG).is_some_and(|flag| flag & 0x80 != 0)
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.cgb_mode = model.is_cgb() && self.supports_cgb();
+        self.sgb = model.is_sgb().then(|| Sgb::new(&self.cartridge));
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // Sets up colours for a monochrome game the way the CGB boot ROM does,
+    // background palette 0 and sprite palettes 0 and 1 stand in for BGP,
+    // OBP0 and OBP1.
+    pub fn set_compatibility_palettes(&mut self, [bg, obj0, obj1]: [[u16; 4]; 3]) {
+        for colour in 0..4 {
+            self.bg_palettes.set_colour(0, colour as u8, bg[colour]);
+            self.obj_palettes.set_colour(0, colour as u8, obj0[colour]);
+            self.obj_palettes.set_colour(1, colour as u8, obj1[colour]);
+        }
+
+        self.compatibility_palettes = true;
+    }
+
+    pub fn has_compatibility_palettes(&self) -> bool {
+        self.compatibility_palettes
+    }
+
+    pub fn bg_palettes(&self) -> &ColourPalettes {
+        &self.bg_palettes
+    }
+
+    pub fn obj_palettes(&self) -> &ColourPalettes {
+        &self.obj_palettes
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called by STOP, switches speed if the switch was prepared through KEY1.
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_prepared {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_prepared = false;
+        true
+    }
+
+    // Cycles the CPU has to wait for while a VRAM DMA holds the bus.
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        let cycles = self.stall_cycles;
+        self.stall_cycles = 0;
+
+        cycles
+    }
+
+    // Called by the PPU as it enters HBlank on a visible line.
+    pub fn hblank_started(&mut self) {
+        if self.vram_dma.hblank_active {
+            self.copy_vram_dma_block();
+
+            if self.vram_dma.remaining_blocks == 0 {
+                self.vram_dma.hblank_active = false;
+            }
+        }
+    }
+
+    fn write_vram_dma_control(&mut self, data: u8) {
+        if self.vram_dma.hblank_active && data & 0x80 == 0 {
+            // Cancels the HBlank transfer, the remaining length stays readable
+            self.vram_dma.hblank_active = false;
+            return;
+        }
+
+        self.vram_dma.remaining_blocks = (data & 0x7F) + 1;
+
+        if data & 0x80 != 0 {
+            self.vram_dma.hblank_active = true;
+        }
+        else {
+            // General purpose DMA copies everything at once
+            while self.vram_dma.remaining_blocks > 0 {
+                self.copy_vram_dma_block();
+            }
+        }
+    }
+
+    fn copy_vram_dma_block(&mut self) {
+        for _ in 0..HDMA_BLOCK_SIZE {
+            let byte = self.read_internal(self.vram_dma.source as usize);
+            let destination = VRAM_START + (self.vram_dma.destination & 0x1FFF) as usize;
+            self.write_internal(destination, byte);
+
+            self.vram_dma.source = self.vram_dma.source.wrapping_add(1);
+            self.vram_dma.destination = (self.vram_dma.destination + 1) & 0x1FFF;
+        }
+
+        self.vram_dma.remaining_blocks -= 1;
+        self.stall_cycles += if self.double_speed { HDMA_BLOCK_STALL * 2 } else { HDMA_BLOCK_STALL };
+    }
+
+    // Without an MBC the switchable area always holds bank 1
+    pub fn rom_bank(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(1),
+            _ => None
+        }
+    }
+
+    pub fn read_vram(&self, bank: usize, addr: usize) -> u8 {
+        self.vram[bank * VRAM_BANK_SIZE + addr - VRAM_START]
+    }
+
+    pub fn write_vram(&mut self, bank: usize, addr: usize, data: u8) {
+        self.vram[bank * VRAM_BANK_SIZE + addr - VRAM_START] = data;
+    }
+
+    // Bank 0 is always at 0xC000, the others take turns at 0xD000
+    pub fn read_wram(&self, bank: usize, offset: usize) -> u8 {
+        self.wram[bank * WRAM_BANK_SIZE + offset]
+    }
+
+    pub fn write_wram(&mut self, bank: usize, offset: usize, data: u8) {
+        self.wram[bank * WRAM_BANK_SIZE + offset] = data;
+    }
+
+    // Changes a byte of the cartridge, and of the copy mapped for the CPU.
+    pub fn patch_rom(&mut self, offset: usize, data: u8) {
+        if let Some(byte) = self.cartridge.get_mut(offset) {
+            *byte = data;
+        }
+
+        if offset < ROM_SIZE {
+            self.ram[offset] = data;
+        }
+    }
+
+    pub fn rom_path(&self) -> Option<&str> {
+        self.rom_path.as_deref()
+    }
+
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_u16(self.stack_pointer);
+        writer.write_bool(self.boot_rom_mapped);
+        self.serial.save_state(writer);
+        self.oam_dma.save_state(writer);
+        writer.write_bytes(&self.vram);
+        writer.write_u8(self.vram_bank as u8);
+        writer.write_bytes(&self.wram);
+        writer.write_u8(self.wram_bank as u8);
+        self.bg_palettes.save_state(writer);
+        self.obj_palettes.save_state(writer);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_prepared);
+        self.vram_dma.save_state(writer);
+        writer.write_u32(self.stall_cycles);
+        writer.write_u8(self.buttons);
+
+        writer.write_bool(self.sgb.is_some());
+        if let Some(sgb) = self.sgb.as_ref() {
+            sgb.save_state(writer);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let ram = reader.read_bytes(0x10000)?;
+        self.ram.copy_from_slice(ram);
+        self.stack_pointer = reader.read_u16()?;
+        self.boot_rom_mapped = reader.read_bool()? && self.has_boot_rom();
+        self.serial.load_state(reader)?;
+        self.oam_dma.load_state(reader)?;
+        self.vram.copy_from_slice(reader.read_bytes(VRAM_BANK_SIZE * VRAM_BANKS)?);
+        self.vram_bank = reader.read_u8()? as usize % VRAM_BANKS;
+        self.wram.copy_from_slice(reader.read_bytes(WRAM_BANK_SIZE * WRAM_BANKS)?);
+        self.wram_bank = (reader.read_u8()? as usize % WRAM_BANKS).max(1);
+        self.bg_palettes.load_state(reader)?;
+        self.obj_palettes.load_state(reader)?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_prepared = reader.read_bool()?;
+        self.vram_dma.load_state(reader)?;
+        self.stall_cycles = reader.read_u32()?;
+        self.buttons = reader.read_u8()?;
+
+        if reader.read_bool()? != self.sgb.is_some() {
+            return Err("Save state was made for a different model".to_string());
+        }
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.load_state(reader)?;
+        }
+
         Ok(())
     }
 
+    pub fn step(&mut self, ticks: u32) {
+        if self.serial.step(ticks) {
+            self.request_interrupt(SERIAL_INTERRUPT);
+        }
+
+        self.step_oam_dma(ticks);
+    }
+
+    // Copies one byte every 4 cycles, 640 cycles for the whole of OAM.
+    fn step_oam_dma(&mut self, ticks: u32) {
+        if !self.oam_dma.active {
+            return;
+        }
+
+        self.oam_dma.cycles += ticks;
+
+        while self.oam_dma.active && self.oam_dma.cycles >= DMA_CYCLES_PER_BYTE {
+            self.oam_dma.cycles -= DMA_CYCLES_PER_BYTE;
+
+            let mut source = (self.oam_dma.source + self.oam_dma.index) as usize;
+            // Like echo RAM, pages 0xFE and 0xFF are read from WRAM
+            if source > ECHO_END {
+                source -= ECHO_START - WRAM_START;
+            }
+
+            let byte = self.read_internal(source);
+            self.ram[OAM_START + self.oam_dma.index as usize] = byte;
+            self.oam_dma.current_byte = byte;
+
+            self.oam_dma.index += 1;
+            self.oam_dma.active = self.oam_dma.index < OAM_SIZE;
+        }
+    }
+
+    pub fn set_oam_scan_row(&mut self, row: Option<usize>) {
+        self.oam_scan_row = row;
+    }
+
+    // Called when the CPU increments or decrements a 16-bit register. If it
+    // points into OAM during the scan, the row being read gets mixed with the
+    // one before it.
+    pub fn oam_bug_inc_dec(&mut self, addr: u16) {
+        let addr = addr as usize;
+        if !self.model.has_oam_bug() || !(OAM_START..=OAM_END).contains(&addr) {
+            return;
+        }
+
+        let row = match self.oam_scan_row {
+            Some(row) if row > 0 => OAM_START + row * OAM_ROW_SIZE,
+            _ => return,
+        };
+        let previous = row - OAM_ROW_SIZE;
+
+        let word = |memory: &Memory, addr: usize| memory.ram[addr] as u16 | (memory.ram[addr + 1] as u16) << 8;
+        let (a, b, c) = (word(self, row), word(self, previous), word(self, previous + 4));
+        let corrupted = ((a ^ c) & (b ^ c)) ^ c;
+
+        self.ram[row] = corrupted as u8;
+        self.ram[row + 1] = (corrupted >> 8) as u8;
+        self.ram.copy_within(previous + 2..previous + OAM_ROW_SIZE, row + 2);
+    }
+
+    // On the DMG writing STAT acts as if every interrupt source were enabled
+    // for a moment, so the interrupt fires in HBlank, VBlank or on LY=LYC.
+    fn write_stat(&mut self, data: u8) {
+        let status = self.ram[STAT];
+        self.ram[STAT] = (data & !STAT_READ_ONLY) | (status & STAT_READ_ONLY);
+
+        let lcd_on = self.ram[LCDC] & 0x80 != 0;
+        let mode = status & 0x03;
+        if self.model.has_stat_write_bug() && lcd_on && (mode < 2 || self.ram[LY] == self.ram[LYC]) {
+            self.request_interrupt(LCD_STAT_INTERRUPT);
+        }
+    }
+
+    fn read_joypad(&self) -> u8 {
+        let select = self.ram[P1] & P1_SELECT;
+        let buttons = self.sgb.as_ref().and_then(|sgb| sgb.joypad(select)).unwrap_or(!self.selected_buttons(self.buttons) & 0x0F);
+
+        0xC0 | select | buttons
+    }
+
+    // The lines pulled low by the buttons in whichever groups P1 selects.
+    fn selected_buttons(&self, buttons: u8) -> u8 {
+        let select = self.ram[P1];
+        let mut lines = 0;
+
+        if select & P1_DIRECTIONS == 0 {
+            lines |= buttons & 0x0F;
+        }
+        if select & P1_BUTTONS == 0 {
+            lines |= buttons >> 4;
+        }
+        lines
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        let pressed = buttons & !self.buttons;
+        self.buttons = buttons;
+
+        // The interrupt fires when a selected line goes low
+        if self.selected_buttons(pressed) != 0 {
+            self.request_interrupt(JOYPAD_INTERRUPT);
+        }
+    }
+
+    fn write_joypad(&mut self, data: u8) {
+        self.ram[P1] = data & P1_SELECT;
+
+        if let Some(mut sgb) = self.sgb.take() {
+            if let Some(transfer) = sgb.write_p1(data) {
+                sgb.complete_transfer(transfer, &self.sgb_transfer_data());
+            }
+            self.sgb = Some(sgb);
+        }
+    }
+
+    // The SGB copies what the game puts on screen, the first 256 tiles of
+    // the background map in display order.
+    fn sgb_transfer_data(&self) -> Vec<u8> {
+        let control = self.ram[LCDC];
+        let map = if control & 0x08 != 0 { 0x9C00 } else { 0x9800 };
+        let mut data = Vec::with_capacity(sgb::TRANSFER_SIZE);
+
+        for index in 0..sgb::TRANSFER_SIZE / 16 {
+            let tile = self.read_vram(0, map + (index / 20) * 32 + index % 20);
+            let tile_addr = if control & 0x10 != 0 {
+                VRAM_START + tile as usize * 16
+            }
+            else {
+                (0x9000 + tile as i8 as isize * 16) as usize
+            };
+
+            data.extend((0..16).map(|offset| self.read_vram(0, tile_addr + offset)));
+        }
+
+        data
+    }
+
+    fn request_interrupt(&mut self, interrupt: u8) {
+        self.ram[INTERRUPT_FLAG] |= interrupt;
+    }
+
+    // While OAM DMA runs the CPU can only reach HRAM, everything else sees
+    // the byte being transferred.
+    fn cpu_blocked(&self, addr: usize) -> bool {
+        self.oam_dma.active && !(HRAM_START..=HRAM_END).contains(&addr)
+    }
+
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    pub fn has_cheats(&self) -> bool {
+        !self.cheats.is_empty()
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    // GameShark codes keep their values in place by rewriting them each frame.
+    pub fn apply_cheats(&mut self) {
+        for (bank, addr, value) in self.cheats.frame_writes() {
+            match bank {
+                Some(bank) => self.write_wram(bank, (addr as usize) & (WRAM_BANK_SIZE - 1), value),
+                None => self.write_internal(addr as usize, value),
+            }
+        }
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Vec<Watchpoint> {
+        &mut self.watchpoints
+    }
+
+    // Returns the first watched access since the last call.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&self, addr: usize, write: bool, value: u8) {
+        let addr = addr as u16;
+        let watched = self.watchpoints.iter().any(|watchpoint| {
+            (watchpoint.start..=watchpoint.end).contains(&addr) && if write { watchpoint.write } else { watchpoint.read }
+        });
+
+        if watched && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { addr, write, value }));
+        }
+    }
+
     pub fn read_memory(&self, addr: usize) -> u8 {
-        self.ram[addr]
+        let value = self.read_bus(addr);
+
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, false, value);
+        }
+
+        value
+    }
+
+    // Reads as the CPU would without it counting for watchpoints, for bytes
+    // fetched before it's known whether the instruction uses them.
+    pub fn peek_memory(&self, addr: usize) -> u8 {
+        self.read_bus(addr)
+    }
+
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    fn read_bus(&self, addr: usize) -> u8 {
+        if self.cpu_blocked(addr) {
+            return self.oam_dma.current_byte;
+        }
+
+        match addr {
+            P1 => self.read_joypad(),
+            serial::SB => self.serial.read_data(),
+            serial::SC => self.serial.read_control(),
+            VBK if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            SVBK if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            BCPS if self.cgb_mode => self.bg_palettes.read_specification(),
+            BCPD if self.cgb_mode => self.bg_palettes.read_data(),
+            OCPS if self.cgb_mode => self.obj_palettes.read_specification(),
+            OCPD if self.cgb_mode => self.obj_palettes.read_data(),
+            KEY1 if self.cgb_mode => {
+                let speed = if self.double_speed { 0x80 } else { 0x00 };
+                0x7E | speed | self.speed_switch_prepared as u8
+            }
+            HDMA5 if self.cgb_mode => self.vram_dma.read_control(),
+            VBK | SVBK | BCPS | BCPD | OCPS | OCPD | KEY1 | HDMA5 => 0xFF,
+            // The DMA source and destination are write only
+            HDMA1..=HDMA4 => 0xFF,
+            _ => self.read_internal(addr)
+        }
     }
 
     pub fn write_memory(&mut self, addr: usize, data: u8) {
-        self.ram[addr] = data;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, true, data);
+        }
+
+        if self.cpu_blocked(addr) {
+            return;
+        }
+
+        match addr {
+            P1 => self.write_joypad(data),
+            serial::SB => self.serial.write_data(data),
+            serial::SC => self.serial.write_control(data),
+            DMA => {
+                self.ram[addr] = data;
+                self.oam_dma.start(data);
+            }
+            STAT => self.write_stat(data),
+            BOOT_ROM_DISABLE => {
+                self.ram[addr] = data;
+                self.boot_rom_mapped &= data == 0;
+            }
+            VBK if self.cgb_mode => self.vram_bank = (data & 0x01) as usize,
+            // Selecting bank 0 maps bank 1
+            SVBK if self.cgb_mode => self.wram_bank = ((data & 0x07) as usize).max(1),
+            BCPS if self.cgb_mode => self.bg_palettes.write_specification(data),
+            BCPD if self.cgb_mode => self.bg_palettes.write_data(data),
+            OCPS if self.cgb_mode => self.obj_palettes.write_specification(data),
+            OCPD if self.cgb_mode => self.obj_palettes.write_data(data),
+            KEY1 if self.cgb_mode => self.speed_switch_prepared = data & SPEED_SWITCH_PREPARE != 0,
+            HDMA1 if self.cgb_mode => self.vram_dma.source = (self.vram_dma.source & 0x00FF) | (data as u16) << 8,
+            HDMA2 if self.cgb_mode => self.vram_dma.source = (self.vram_dma.source & 0xFF00) | (data & 0xF0) as u16,
+            HDMA3 if self.cgb_mode => {
+                self.vram_dma.destination = (self.vram_dma.destination & 0x00FF) | ((data & 0x1F) as u16) << 8;
+            }
+            HDMA4 if self.cgb_mode => {
+                self.vram_dma.destination = (self.vram_dma.destination & 0xFF00) | (data & 0xF0) as u16;
+            }
+            HDMA5 if self.cgb_mode => self.write_vram_dma_control(data),
+            VBK | SVBK | BCPS | BCPD | OCPS | OCPD | KEY1 | HDMA1..=HDMA5 => {}
+            _ => self.write_internal(addr, data)
+        }
+    }
+
+    // Access for the rest of the hardware, which isn't subject to the
+    // restrictions on the CPU's bus.
+    pub fn read_internal(&self, addr: usize) -> u8 {
+        match self.banked_offset(addr) {
+            Some(Banked::Vram(offset)) => self.vram[offset],
+            Some(Banked::Wram(offset)) => self.wram[offset],
+            None if self.boot_rom_overlays(addr) => self.boot_rom[addr],
+            // Game Genie codes sit between the cartridge and everything reading it
+            None if addr < ROM_SIZE && !self.cheats.is_empty() => self.cheats.patch_read(addr as u16, self.ram[addr]),
+            None => self.ram[addr]
+        }
+    }
+
+    fn boot_rom_overlays(&self, addr: usize) -> bool {
+        self.boot_rom_mapped
+            && addr < self.boot_rom.len()
+            && !(BOOT_ROM_HEADER_START..=BOOT_ROM_HEADER_END).contains(&addr)
+    }
+
+    pub fn write_internal(&mut self, addr: usize, data: u8) {
+        match self.banked_offset(addr) {
+            Some(Banked::Vram(offset)) => self.vram[offset] = data,
+            Some(Banked::Wram(offset)) => self.wram[offset] = data,
+            None => self.ram[addr] = data
+        }
+    }
+
+    fn banked_offset(&self, addr: usize) -> Option<Banked> {
+        match addr {
+            0x8000..=0x9FFF => Some(Banked::Vram(self.vram_bank * VRAM_BANK_SIZE + addr - VRAM_START)),
+            0xC000..=0xCFFF => Some(Banked::Wram(addr - WRAM_START)),
+            0xD000..=0xDFFF => Some(Banked::Wram(self.wram_bank * WRAM_BANK_SIZE + addr - WRAM_START - WRAM_BANK_SIZE)),
+            ECHO_START..=ECHO_END => self.banked_offset(addr - (ECHO_START - WRAM_START)),
+            _ => None
+        }
     }
 
     pub fn set_stack_pointer(&mut self, stack_pointer: u16) {
@@ -51,19 +855,114 @@ impl Memory {
         let byte_two = (value & 0xFF) as u8;
 
         self.stack_pointer -= 1;
-        self.ram[self.stack_pointer as usize] = byte_one;
+        self.write_memory(self.stack_pointer as usize, byte_one);
 
         self.stack_pointer -= 1;
-        self.ram[self.stack_pointer as usize] = byte_two;
+        self.write_memory(self.stack_pointer as usize, byte_two);
     }
 
     pub fn pop_16(&mut self) -> u16 {
-        let byte_one = self.ram[self.stack_pointer as usize];
+        let byte_one = self.read_memory(self.stack_pointer as usize);
         self.stack_pointer += 1;
 
-        let byte_two = self.ram[self.stack_pointer as usize];
+        let byte_two = self.read_memory(self.stack_pointer as usize);
         self.stack_pointer += 1;
 
         (byte_two as u16) << 8 | (byte_one as u16)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(memory: &mut Memory, start: usize) {
+        for offset in 0..OAM_SIZE as usize {
+            memory.write_internal(start + offset, offset as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn oam_dma_copies_a_byte_every_four_cycles() {
+        let mut memory = Memory::new();
+        fill(&mut memory, 0xC000);
+        memory.write_memory(DMA, 0xC0);
+
+        memory.step(DMA_CYCLES_PER_BYTE * 10);
+        assert_eq!(memory.read_internal(OAM_START + 9), 9 ^ 0x5A);
+        assert_eq!(memory.read_internal(OAM_START + 10), 0);
+
+        memory.step(DMA_CYCLES_PER_BYTE * (OAM_SIZE as u32 - 10) - 1);
+        assert_ne!(memory.read_internal(OAM_START + OAM_SIZE as usize - 1), (OAM_SIZE - 1) as u8 ^ 0x5A);
+        memory.step(1);
+
+        for offset in 0..OAM_SIZE as usize {
+            assert_eq!(memory.read_internal(OAM_START + offset), offset as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn cpu_only_reaches_hram_during_oam_dma() {
+        let mut memory = Memory::new();
+        fill(&mut memory, 0xC000);
+        memory.write_internal(HRAM_START, 0x42);
+        memory.write_memory(DMA, 0xC0);
+        memory.step(DMA_CYCLES_PER_BYTE * 3);
+
+        // Anywhere else the CPU sees the byte being transferred
+        assert_eq!(memory.read_memory(0xC010), 2 ^ 0x5A);
+        assert_eq!(memory.read_memory(HRAM_START), 0x42);
+
+        memory.write_memory(0xC010, 0x00);
+        memory.step(DMA_CYCLES_PER_BYTE * OAM_SIZE as u32);
+        assert_eq!(memory.read_memory(0xC010), 0x10 ^ 0x5A);
+    }
+
+    #[test]
+    fn oam_dma_from_above_echo_ram_reads_wram() {
+        let mut memory = Memory::new();
+        fill(&mut memory, 0xDE00);
+        memory.write_memory(DMA, 0xFE);
+        memory.step(DMA_CYCLES_PER_BYTE * OAM_SIZE as u32);
+
+        assert_eq!(memory.read_internal(OAM_START + 0x20), 0x20 ^ 0x5A);
+    }
+
+    #[test]
+    fn sgb_multiplayer_joypads() {
+        let mut memory = Memory::new();
+        memory.cartridge = vec![0; 0x150];
+        memory.cartridge[0x0146] = 0x03;
+        memory.cartridge[0x014B] = 0x33;
+        memory.set_model(Model::Sgb);
+
+        // MLT_REQ for two players, one bit at a time with P14 low for a 0
+        // and P15 low for a 1, then a stop bit
+        let mut packet = [0; 16];
+        packet[0] = 0x89;
+        packet[1] = 0x01;
+        memory.write_memory(P1, 0x00);
+        memory.write_memory(P1, 0x30);
+        for bit in 0..packet.len() * 8 {
+            memory.write_memory(P1, if packet[bit / 8] >> (bit % 8) & 0x01 != 0 { 0x10 } else { 0x20 });
+            memory.write_memory(P1, 0x30);
+        }
+        memory.write_memory(P1, 0x20);
+        memory.write_memory(P1, 0x30);
+
+        memory.set_buttons(JOYPAD_A);
+        assert_eq!(memory.read_memory(P1) & 0x0F, 0x0F);
+        memory.write_memory(P1, 0x10);
+        assert_eq!(memory.read_memory(P1) & 0x0F, 0x0E);
+
+        // Raising P15 moves on to the second joypad, which has nothing pressed
+        memory.write_memory(P1, 0x30);
+        assert_eq!(memory.read_memory(P1) & 0x0F, 0x0E);
+        memory.write_memory(P1, 0x10);
+        assert_eq!(memory.read_memory(P1) & 0x0F, 0x0F);
+
+        memory.write_memory(P1, 0x30);
+        memory.write_memory(P1, 0x10);
+        assert_eq!(memory.read_memory(P1) & 0x0F, 0x0E);
+    }
 }
\ No newline at end of file
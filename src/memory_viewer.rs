@@ -0,0 +1,320 @@
+extern crate sdl2;
+
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+
+use crate::memory::Memory;
+use crate::viewer::{ViewerWindow, GLYPH_ADVANCE, GLYPH_HEIGHT, TEXT};
+
+const BYTES_PER_ROW: usize = 16;
+const VISIBLE_ROWS: usize = 32;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+const MARGIN: usize = 2;
+const ROWS_Y: usize = MARGIN + LINE_HEIGHT * 2;
+const HEX_X: usize = MARGIN + GLYPH_ADVANCE * 5;
+const HEX_ADVANCE: usize = GLYPH_ADVANCE * 3;
+const ASCII_X: usize = HEX_X + HEX_ADVANCE * BYTES_PER_ROW + GLYPH_ADVANCE;
+const WIDTH: usize = ASCII_X + GLYPH_ADVANCE * BYTES_PER_ROW + MARGIN;
+const HEIGHT: usize = ROWS_Y + LINE_HEIGHT * VISIBLE_ROWS;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const VRAM_START: usize = 0x8000;
+const VRAM_SIZE: usize = 0x2000;
+const WRAM_START: usize = 0xC000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const OAM_START: usize = 0xFE00;
+const OAM_SIZE: usize = 0xA0;
+const IO_START: usize = 0xFF00;
+const IO_SIZE: usize = 0x80;
+// Includes the interrupt enable register at the very top
+const HRAM_START: usize = 0xFF80;
+const HRAM_SIZE: usize = 0x80;
+
+// Frames a changed byte stays highlighted
+const HIGHLIGHT_FRAMES: u8 = 30;
+
+const CHANGED: (u8, u8, u8) = (255, 220, 64);
+const CURSOR: (u8, u8, u8) = (48, 96, 200);
+const SIDE_EFFECTS: (u8, u8, u8) = (255, 96, 96);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Region {
+    Rom(usize),
+    Vram(usize),
+    Wram(usize),
+    Oam,
+    Io,
+    Hram,
+}
+
+impl Region {
+    const ORDER: [Region; 6] = [Region::Rom(0), Region::Vram(0), Region::Wram(0), Region::Oam, Region::Io, Region::Hram];
+
+    fn name(&self) -> String {
+        match *self {
+            Region::Rom(bank) => format!("ROM BANK {:02X}", bank),
+            Region::Vram(bank) => format!("VRAM BANK {}", bank),
+            Region::Wram(bank) => format!("WRAM BANK {}", bank),
+            Region::Oam => "OAM".to_string(),
+            Region::Io => "I/O".to_string(),
+            Region::Hram => "HRAM".to_string(),
+        }
+    }
+
+    // Where the CPU sees the first byte when the bank is mapped
+    fn start(&self) -> usize {
+        match *self {
+            Region::Rom(0) => 0x0000,
+            Region::Rom(_) => ROM_BANK_SIZE,
+            Region::Vram(_) => VRAM_START,
+            Region::Wram(0) => WRAM_START,
+            Region::Wram(_) => WRAM_START + WRAM_BANK_SIZE,
+            Region::Oam => OAM_START,
+            Region::Io => IO_START,
+            Region::Hram => HRAM_START,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Region::Rom(_) => ROM_BANK_SIZE,
+            Region::Vram(_) => VRAM_SIZE,
+            Region::Wram(_) => WRAM_BANK_SIZE,
+            Region::Oam => OAM_SIZE,
+            Region::Io => IO_SIZE,
+            Region::Hram => HRAM_SIZE,
+        }
+    }
+
+    fn with_bank(&self, bank: usize) -> Region {
+        match *self {
+            Region::Rom(_) => Region::Rom(bank),
+            Region::Vram(_) => Region::Vram(bank),
+            Region::Wram(_) => Region::Wram(bank),
+            other => other,
+        }
+    }
+
+    fn bank(&self) -> Option<usize> {
+        match *self {
+            Region::Rom(bank) | Region::Vram(bank) | Region::Wram(bank) => Some(bank),
+            _ => None,
+        }
+    }
+
+    // I/O shows what the CPU would read, the rest shows what is stored.
+    fn read(&self, memory: &Memory, offset: usize) -> u8 {
+        match *self {
+            Region::Rom(bank) => memory.cartridge().get(bank * ROM_BANK_SIZE + offset).copied().unwrap_or(0xFF),
+            Region::Vram(bank) => memory.read_vram(bank, VRAM_START + offset),
+            Region::Wram(bank) => memory.read_wram(bank, offset),
+            Region::Io => memory.read_memory(IO_START + offset),
+            _ => memory.read_internal(self.start() + offset),
+        }
+    }
+
+    // Only OAM, I/O and HRAM go through the CPU's bus, and only when asked,
+    // so an edit can't start a DMA or a serial transfer by accident.
+    fn write(&self, memory: &mut Memory, offset: usize, value: u8, side_effects: bool) {
+        match *self {
+            Region::Rom(bank) => memory.patch_rom(bank * ROM_BANK_SIZE + offset, value),
+            Region::Vram(bank) => memory.write_vram(bank, VRAM_START + offset, value),
+            Region::Wram(bank) => memory.write_wram(bank, offset, value),
+            _ if side_effects => memory.write_memory(self.start() + offset, value),
+            _ => memory.write_internal(self.start() + offset, value),
+        }
+    }
+}
+
+// Shows a region of memory as hex and ASCII, with bytes that changed
+// recently picked out. Keys:
+//   Tab / Shift+Tab   next and previous region
+//   [ and ]           previous and next bank
+//   arrows, PageUp/Down  move the cursor
+//   0-9, A-F          type a new value for the byte under the cursor, unless
+//                     a movie is running
+//   Insert            toggle going through the CPU's bus for I/O writes
+pub struct MemoryViewer {
+    window: ViewerWindow,
+    region: Region,
+    cursor: usize,
+    top_row: usize,
+    // High nibble typed so far
+    pending: Option<u8>,
+    side_effects: bool,
+    previous: Vec<u8>,
+    ages: Vec<u8>,
+    cgb: bool,
+}
+
+impl MemoryViewer {
+    pub fn new(sdl_context: &sdl2::Sdl, cgb: bool) -> Self {
+        MemoryViewer {
+            window: ViewerWindow::new(sdl_context, "dustboy memory", WIDTH, HEIGHT),
+            region: Region::Wram(0),
+            cursor: 0,
+            top_row: 0,
+            pending: None,
+            side_effects: false,
+            previous: Vec::new(),
+            ages: Vec::new(),
+            cgb,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.window.id()
+    }
+
+    fn bank_count(&self, memory: &Memory) -> usize {
+        match self.region {
+            Region::Rom(_) => (memory.cartridge().len() / ROM_BANK_SIZE).max(1),
+            Region::Vram(_) if self.cgb => 2,
+            Region::Wram(_) if self.cgb => 8,
+            Region::Wram(_) => 2,
+            _ => 1,
+        }
+    }
+
+    fn select(&mut self, region: Region) {
+        self.region = region;
+        self.cursor = 0;
+        self.top_row = 0;
+        self.pending = None;
+        self.previous.clear();
+        self.ages.clear();
+    }
+
+    pub fn handle_event(&mut self, event: &Event, memory: &mut Memory, editable: bool) {
+        let (keycode, keymod) = match *event {
+            Event::KeyDown { keycode: Some(keycode), keymod, .. } => (keycode, keymod),
+            _ => return,
+        };
+
+        let size = self.region.size();
+        let page = BYTES_PER_ROW * VISIBLE_ROWS;
+
+        match keycode {
+            Keycode::Tab => {
+                let index = Region::ORDER.iter().position(|region| region.with_bank(0) == self.region.with_bank(0)).unwrap_or(0);
+                let step = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) { Region::ORDER.len() - 1 } else { 1 };
+                self.select(Region::ORDER[(index + step) % Region::ORDER.len()]);
+            }
+            Keycode::LeftBracket | Keycode::RightBracket => {
+                if let Some(bank) = self.region.bank() {
+                    let count = self.bank_count(memory);
+                    let bank = if keycode == Keycode::RightBracket { (bank + 1) % count } else { (bank + count - 1) % count };
+                    self.select(self.region.with_bank(bank));
+                }
+            }
+            Keycode::Left => self.move_cursor(-1),
+            Keycode::Right => self.move_cursor(1),
+            Keycode::Up => self.move_cursor(-(BYTES_PER_ROW as isize)),
+            Keycode::Down => self.move_cursor(BYTES_PER_ROW as isize),
+            Keycode::PageUp => self.move_cursor(-(page as isize)),
+            Keycode::PageDown => self.move_cursor(page as isize),
+            Keycode::Home => self.move_cursor(-(size as isize)),
+            Keycode::End => self.move_cursor(size as isize),
+            Keycode::Insert => self.side_effects = !self.side_effects,
+            Keycode::Escape => self.pending = None,
+            _ => {
+                let digit = keycode.name().chars().next().filter(|_| keycode.name().len() == 1).and_then(|c| c.to_digit(16));
+                if let Some(digit) = digit.filter(|_| editable) {
+                    self.type_digit(digit as u8, memory);
+                }
+            }
+        }
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let last = self.region.size() as isize - 1;
+        self.cursor = (self.cursor as isize + delta).clamp(0, last) as usize;
+        self.pending = None;
+
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.top_row {
+            self.top_row = row;
+        }
+        else if row >= self.top_row + VISIBLE_ROWS {
+            self.top_row = row + 1 - VISIBLE_ROWS;
+        }
+    }
+
+    fn type_digit(&mut self, digit: u8, memory: &mut Memory) {
+        match self.pending.take() {
+            None => self.pending = Some(digit),
+            Some(high) => {
+                let value = high << 4 | digit;
+                self.region.write(memory, self.cursor, value, self.side_effects);
+
+                // The edit isn't a change made by the game
+                if let Some(previous) = self.previous.get_mut(self.cursor) {
+                    *previous = self.region.read(memory, self.cursor);
+                }
+                self.move_cursor(1);
+            }
+        }
+    }
+
+    pub fn update(&mut self, memory: &Memory) {
+        let size = self.region.size();
+        let current: Vec<u8> = (0..size).map(|offset| self.region.read(memory, offset)).collect();
+
+        if self.previous.len() == size {
+            for (offset, age) in self.ages.iter_mut().enumerate() {
+                *age = if current[offset] != self.previous[offset] { HIGHLIGHT_FRAMES } else { age.saturating_sub(1) };
+            }
+        }
+        else {
+            self.ages = vec![0; size];
+        }
+        self.previous = current;
+
+        self.draw();
+        self.window.present();
+    }
+
+    fn draw(&mut self) {
+        let window = &mut self.window;
+        window.clear();
+
+        let start = self.region.start();
+        let end = start + self.region.size() - 1;
+        window.draw_text(MARGIN, MARGIN, &format!("{}  {:04X}-{:04X}", self.region.name(), start, end));
+
+        if self.side_effects {
+            window.draw_text_in(ASCII_X, MARGIN, "SIDE EFFECTS", SIDE_EFFECTS);
+        }
+
+        let rows = (self.region.size() / BYTES_PER_ROW).max(1);
+        for row in self.top_row..(self.top_row + VISIBLE_ROWS).min(rows) {
+            let y = ROWS_Y + (row - self.top_row) * LINE_HEIGHT;
+            window.draw_text(MARGIN, y, &format!("{:04X}", start + row * BYTES_PER_ROW));
+
+            for column in 0..BYTES_PER_ROW {
+                let offset = row * BYTES_PER_ROW + column;
+                let Some(&value) = self.previous.get(offset) else {
+                    break;
+                };
+
+                let hex_x = HEX_X + column * HEX_ADVANCE;
+                let ascii_x = ASCII_X + column * GLYPH_ADVANCE;
+
+                if offset == self.cursor {
+                    window.fill_rect(hex_x - 1, y - 1, GLYPH_ADVANCE * 2 + 1, LINE_HEIGHT, CURSOR);
+                    window.fill_rect(ascii_x - 1, y - 1, GLYPH_ADVANCE + 1, LINE_HEIGHT, CURSOR);
+                }
+
+                let colour = if self.ages.get(offset).is_some_and(|&age| age > 0) { CHANGED } else { TEXT };
+                let hex = match self.pending {
+                    Some(high) if offset == self.cursor => format!("{:X}_", high),
+                    _ => format!("{:02X}", value),
+                };
+                window.draw_text_in(hex_x, y, &hex, colour);
+
+                let character = if value.is_ascii_graphic() { value as char } else { '.' };
+                window.draw_text_in(ascii_x, y, &character.to_string(), colour);
+            }
+        }
+    }
+}
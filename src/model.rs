@@ -0,0 +1,150 @@
+use crate::compatibility;
+
+const HEADER_CHECKSUM: usize = 0x014D;
+
+// Registers left behind by every boot ROM, as (address, value)
+const IO_DEFAULTS: [(usize, u8); 27] = [
+    (0xFF00, 0xCF),
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF40, 0x91),
+    (0xFF41, 0x85),
+    (0xFF47, 0xFC),
+    (0xFFFF, 0x00),
+];
+
+const DIV: usize = 0xFF04;
+const NR52: usize = 0xFF26;
+const DMA: usize = 0xFF46;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub fn parse(value: &str) -> Option<Model> {
+        match value.to_ascii_lowercase().as_str() {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "sgb2" => Some(Model::Sgb2),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    // Writing STAT briefly enables every STAT interrupt source
+    pub fn has_stat_write_bug(self) -> bool {
+        !self.is_cgb()
+    }
+
+    // 16-bit increments and decrements pointing into OAM during mode 2
+    // corrupt the row the PPU is scanning
+    pub fn has_oam_bug(self) -> bool {
+        !self.is_cgb()
+    }
+
+    // AF, BC, DE and HL when the boot ROM jumps to the cartridge. Games tell
+    // the models apart by A, and the AGB by bit 0 of B.
+    pub fn post_boot_registers(self, cartridge: &[u8], cgb_mode: bool) -> [u16; 4] {
+        // The DMG boot ROM's header check leaves H and C set unless the
+        // checksum happens to be zero
+        let dmg_flags = if cartridge.get(HEADER_CHECKSUM).is_some_and(|checksum| *checksum == 0) { 0x80 } else { 0xB0 };
+
+        match self {
+            Model::Dmg0 => [0x0100, 0xFF13, 0x00C1, 0x8403],
+            Model::Dmg => [0x0100 | dmg_flags, 0x0013, 0x00D8, 0x014D],
+            Model::Mgb => [0xFF00 | dmg_flags, 0x0013, 0x00D8, 0x014D],
+            Model::Sgb => [0x0100, 0x0014, 0x0000, 0xC060],
+            Model::Sgb2 => [0xFF00, 0x0014, 0x0000, 0xC060],
+            Model::Cgb | Model::Agb => {
+                // Running a monochrome game the boot ROM leaves its title
+                // checksum in B
+                let (b, de, hl) = if cgb_mode {
+                    (0x00, 0xFF56, 0x000D)
+                }
+                else {
+                    (compatibility::title_checksum(cartridge).unwrap_or(0), 0x0008, 0x007C)
+                };
+
+                if self == Model::Cgb {
+                    [0x1180, (b as u16) << 8, de, hl]
+                }
+                else {
+                    // The AGB boot ROM ends with INC B, whose flags replace
+                    // those left by the CGB code
+                    let b = b.wrapping_add(1);
+                    let flags = if b == 0 { 0x80 } else { 0x00 } | if b & 0x0F == 0 { 0x20 } else { 0x00 };
+                    [0x1100 | flags, (b as u16) << 8, de, hl]
+                }
+            }
+        }
+    }
+
+    // I/O registers as the boot ROM leaves them, as (address, value).
+    pub fn io_defaults(self) -> Vec<(usize, u8)> {
+        let mut defaults = IO_DEFAULTS.to_vec();
+
+        // Only the upper byte of the internal divider shows in DIV, the
+        // values for the SGB and CGB families aren't known
+        match self {
+            Model::Dmg0 => defaults.push((DIV, 0x18)),
+            Model::Dmg | Model::Mgb => defaults.push((DIV, 0xAB)),
+            _ => defaults.push((DIV, 0x00)),
+        }
+
+        defaults.push((NR52, if self.is_sgb() { 0xF0 } else { 0xF1 }));
+        defaults.push((DMA, if self.is_cgb() { 0x00 } else { 0xFF }));
+
+        defaults
+    }
+}
@@ -0,0 +1,338 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+use crate::memory::Memory;
+use crate::model::Model;
+use crate::movie_import;
+use crate::ppu::PPU;
+use crate::state::{self, StateReader, StateWriter};
+
+const MAGIC: &[u8; 4] = b"DBMV";
+const EXTENSION: &str = "dbm";
+const VERSION: u16 = 1;
+
+const START_POWER_ON: u8 = 0;
+const START_STATE: u8 = 1;
+const START_SAVE_RAM: u8 = 2;
+
+const CART_RAM_START: usize = 0xA000;
+const CART_RAM_SIZE: usize = 0x2000;
+
+pub enum Start {
+    PowerOn,
+    // A save state taken when recording began
+    State(Vec<u8>),
+    // Power-on with the cartridge RAM filled in, as imported movies can start
+    SaveRam(Vec<u8>),
+}
+
+// The joypad state for every frame, with what's needed to get the machine
+// into the same state the recording began from.
+pub struct Movie {
+    pub rom_checksum: u32,
+    pub model: Model,
+    pub boot_rom: bool,
+    pub start: Start,
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(memory: &Memory, start: Start) -> Self {
+        Movie {
+            rom_checksum: memory.rom_checksum(),
+            model: memory.model(),
+            boot_rom: memory.has_boot_rom(),
+            start,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| e.to_string())
+    }
+
+    // Layout: magic, version (u16), ROM checksum (u32), model name, boot ROM
+    // flag, start, then the frame count (u32) and a byte per frame.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(MAGIC);
+        writer.write_u16(VERSION);
+        writer.write_u32(self.rom_checksum);
+
+        let model = self.model.name().as_bytes();
+        writer.write_u8(model.len() as u8);
+        writer.write_bytes(model);
+        writer.write_bool(self.boot_rom);
+
+        match &self.start {
+            Start::PowerOn => writer.write_u8(START_POWER_ON),
+            Start::State(state) => {
+                writer.write_u8(START_STATE);
+                writer.write_u32(state.len() as u32);
+                writer.write_bytes(state);
+            }
+            Start::SaveRam(ram) => {
+                writer.write_u8(START_SAVE_RAM);
+                writer.write_u32(ram.len() as u32);
+                writer.write_bytes(ram);
+            }
+        }
+
+        writer.write_u32(self.frames.len() as u32);
+        writer.write_bytes(&self.frames);
+
+        writer.into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Movie, String> {
+        let mut reader = StateReader::new(data);
+
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err("Not a dustboy movie".to_string());
+        }
+
+        let version = reader.read_u16()?;
+        if version != VERSION {
+            return Err(format!("Unsupported movie version {}", version));
+        }
+
+        let rom_checksum = reader.read_u32()?;
+        let length = reader.read_u8()? as usize;
+        let name = String::from_utf8_lossy(reader.read_bytes(length)?).into_owned();
+        let model = Model::parse(&name).ok_or_else(|| format!("Unknown model {}", name))?;
+        let boot_rom = reader.read_bool()?;
+
+        let start = match reader.read_u8()? {
+            START_POWER_ON => Start::PowerOn,
+            START_STATE => {
+                let length = reader.read_u32()? as usize;
+                Start::State(reader.read_bytes(length)?.to_vec())
+            }
+            START_SAVE_RAM => {
+                let length = reader.read_u32()? as usize;
+                Start::SaveRam(reader.read_bytes(length)?.to_vec())
+            }
+            start => return Err(format!("Unknown movie start {}", start)),
+        };
+
+        let length = reader.read_u32()? as usize;
+        let frames = reader.read_bytes(length)?.to_vec();
+
+        Ok(Movie { rom_checksum, model, boot_rom, start, frames })
+    }
+
+    // A different ROM can't play back at all. Other differences are only
+    // warned about since the movie may still sync.
+    pub fn check(&self, memory: &Memory) -> Result<(), String> {
+        if self.rom_checksum != memory.rom_checksum() {
+            return Err(format!("Movie was recorded with a different ROM, CRC32 {:08X}", self.rom_checksum));
+        }
+
+        if self.model != memory.model() {
+            eprintln!("Warning: movie was recorded on {}, running on {}", self.model.name(), memory.model().name());
+        }
+
+        if self.boot_rom != memory.has_boot_rom() {
+            eprintln!("Warning: movie was recorded {} a boot ROM", if self.boot_rom { "with" } else { "without" });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Recording,
+    Playing,
+    Finished,
+}
+
+pub struct MovieSession {
+    movie: Movie,
+    path: PathBuf,
+    mode: Mode,
+    // While playing, read-write lets the player take over from the current
+    // frame, dropping the rest of the movie.
+    read_only: bool,
+    frame: usize,
+    changed: bool,
+}
+
+impl MovieSession {
+    pub fn record(path: &str, movie: Movie) -> Self {
+        MovieSession { movie, path: PathBuf::from(path), mode: Mode::Recording, read_only: false, frame: 0, changed: true }
+    }
+
+    // BK2 and VBM movies are converted as they're loaded. Recording over
+    // one saves a dustboy movie next to it rather than replacing it.
+    pub fn play(path: &str, read_only: bool, memory: &Memory) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+
+        let (movie, path) = if data.starts_with(MAGIC) {
+            (Movie::parse(&data), PathBuf::from(path))
+        }
+        else {
+            (movie_import::import(&data, memory), Path::new(path).with_extension(EXTENSION))
+        };
+
+        let movie = movie.map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(MovieSession { movie, path, mode: Mode::Playing, read_only, frame: 0, changed: false })
+    }
+
+    // Puts the machine where the movie starts.
+    pub fn begin(&self, cpu: &mut CPU, memory: &mut Memory, ppu: &mut PPU) -> Result<(), String> {
+        self.movie.check(memory)?;
+
+        // Cheats change what the game reads without being in the movie
+        if memory.has_cheats() {
+            return Err("Movies can't be recorded or played with cheats loaded, move the .cht file aside".to_string());
+        }
+
+        match &self.movie.start {
+            Start::PowerOn => Ok(()),
+            Start::State(data) => state::load_state(data, cpu, memory, ppu),
+            Start::SaveRam(ram) => {
+                for (offset, &byte) in ram.iter().take(CART_RAM_SIZE).enumerate() {
+                    memory.write_internal(CART_RAM_START + offset, byte);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != Mode::Finished
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.mode == Mode::Playing
+    }
+
+    // The buttons for the next frame, from the movie when playing or the
+    // ones held down otherwise.
+    pub fn next_frame(&mut self, held: u8) -> u8 {
+        if self.mode == Mode::Playing {
+            if !self.read_only && held != 0 {
+                println!("Movie: recording from frame {}", self.frame);
+                self.start_recording();
+            }
+            else if let Some(&buttons) = self.movie.frames.get(self.frame) {
+                self.frame += 1;
+                return buttons;
+            }
+            else {
+                println!("Movie: finished at frame {}", self.frame);
+                if self.read_only {
+                    self.mode = Mode::Finished;
+                }
+                else {
+                    self.start_recording();
+                }
+            }
+        }
+
+        if self.mode == Mode::Recording {
+            self.movie.frames.push(held);
+            self.frame += 1;
+        }
+        held
+    }
+
+    fn start_recording(&mut self) {
+        self.movie.frames.truncate(self.frame);
+        self.mode = Mode::Recording;
+        self.changed = true;
+    }
+
+    pub fn toggle_read_only(&mut self) -> bool {
+        self.read_only = !self.read_only;
+        self.read_only
+    }
+
+    pub fn status(&self) -> String {
+        match self.mode {
+            Mode::Recording => format!("recording frame {}", self.frame),
+            Mode::Playing => format!(
+                "frame {}/{} {}", self.frame, self.movie.frames.len(),
+                if self.read_only { "read-only" } else { "read-write" }
+            ),
+            Mode::Finished => format!("finished, {} frames", self.movie.frames.len()),
+        }
+    }
+
+    // Saves the movie if anything was recorded.
+    pub fn finish(&self) -> Result<(), String> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        self.movie.save(&self.path).map_err(|err| format!("{}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movie(start: Start, frames: &[u8]) -> Movie {
+        Movie { rom_checksum: 0x1234_5678, model: Model::Cgb, boot_rom: true, start, frames: frames.to_vec() }
+    }
+
+    fn session(mode: Mode, read_only: bool, frames: &[u8]) -> MovieSession {
+        MovieSession { movie: movie(Start::PowerOn, frames), path: PathBuf::new(), mode, read_only, frame: 0, changed: false }
+    }
+
+    #[test]
+    fn save_and_parse_round_trip() {
+        for start in [Start::PowerOn, Start::State(vec![1, 2, 3]), Start::SaveRam(vec![0xFF; 8])] {
+            let original = movie(start, &[0x00, 0x01, 0x80, 0x00]);
+            let parsed = Movie::parse(&original.to_bytes()).unwrap();
+
+            assert_eq!(parsed.rom_checksum, original.rom_checksum);
+            assert!(parsed.model == original.model);
+            assert_eq!(parsed.boot_rom, original.boot_rom);
+            assert_eq!(parsed.frames, original.frames);
+            match (&parsed.start, &original.start) {
+                (Start::PowerOn, Start::PowerOn) => {}
+                (Start::State(parsed), Start::State(original)) | (Start::SaveRam(parsed), Start::SaveRam(original)) => {
+                    assert_eq!(parsed, original);
+                }
+                _ => panic!("start changed"),
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_movies_are_refused() {
+        let data = movie(Start::PowerOn, &[0x01; 10]).to_bytes();
+
+        assert!(Movie::parse(&data[..data.len() - 1]).is_err());
+        assert!(Movie::parse(b"DBMX").is_err());
+
+        let mut version = data.clone();
+        version[MAGIC.len()] += 1;
+        assert!(Movie::parse(&version).is_err());
+    }
+
+    #[test]
+    fn read_only_playback_ignores_the_joypad() {
+        let mut session = session(Mode::Playing, true, &[0x01, 0x02]);
+
+        assert_eq!(session.next_frame(0x80), 0x01);
+        assert_eq!(session.next_frame(0x80), 0x02);
+        assert_eq!(session.next_frame(0x80), 0x80);
+        assert!(!session.is_active());
+    }
+
+    #[test]
+    fn read_write_playback_records_from_the_first_press() {
+        let mut session = session(Mode::Playing, false, &[0x01, 0x02, 0x04]);
+
+        assert_eq!(session.next_frame(0x00), 0x01);
+        assert_eq!(session.next_frame(0x10), 0x10);
+        assert_eq!(session.next_frame(0x20), 0x20);
+        assert!(!session.is_playing());
+        assert_eq!(session.movie.frames, [0x01, 0x10, 0x20]);
+    }
+}
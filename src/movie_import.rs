@@ -0,0 +1,322 @@
+use crate::archive;
+use crate::checksum::sha1;
+use crate::memory::{self, Memory};
+use crate::model::Model;
+use crate::movie::{Movie, Start};
+
+const VBM_MAGIC: &[u8] = b"VBM\x1A";
+const VBM_HEADER_SIZE: usize = 0x100;
+const VBM_START_STATE: u8 = 0x01;
+const VBM_START_SAVE_RAM: u8 = 0x02;
+const VBM_SYSTEM_GBA: u8 = 0x01;
+const VBM_SYSTEM_GBC: u8 = 0x02;
+const VBM_SYSTEM_SGB: u8 = 0x04;
+const VBM_OPTION_RTC: u8 = 0x04;
+const VBM_RESET: u16 = 0x0800;
+const VBM_CONTROLLERS: u8 = 0x0F;
+const VBM_FIRST_CONTROLLER: u8 = 0x01;
+// A, B, Select, Start, Right, Left, Up, Down from bit 0
+const VBM_BUTTONS: [u8; 8] = [
+    memory::JOYPAD_A, memory::JOYPAD_B, memory::JOYPAD_SELECT, memory::JOYPAD_START,
+    memory::JOYPAD_RIGHT, memory::JOYPAD_LEFT, memory::JOYPAD_UP, memory::JOYPAD_DOWN,
+];
+
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+const BK2_HEADER: &str = "Header.txt";
+const BK2_INPUT: &str = "Input Log.txt";
+const BK2_SYNC_SETTINGS: &str = "SyncSettings.json";
+const BK2_SAVE_RAM: &str = "SaveRam";
+const BK2_PLATFORMS: [&str; 3] = ["GB", "GBC", "SGB"];
+// Gambatte's order, used when the log doesn't name its buttons
+const BK2_DEFAULT_KEYS: [&str; 9] = ["Up", "Down", "Left", "Right", "Start", "Select", "B", "A", "Power"];
+
+// Converts a BizHawk BK2 or VisualBoyAdvance VBM movie. Other emulators'
+// save states can't be loaded, so only movies starting from power-on or
+// from save RAM are supported.
+pub fn import(data: &[u8], memory: &Memory) -> Result<Movie, String> {
+    if archive::is_zip(data) {
+        import_bk2(data, memory)
+    }
+    else if data.starts_with(VBM_MAGIC) {
+        import_vbm(data, memory)
+    }
+    else {
+        Err("Not a dustboy, BK2 or VBM movie".to_string())
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    let bytes = data.get(offset..offset + 2).ok_or("Movie ends early")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = data.get(offset..offset + 4).ok_or("Movie ends early")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn warn_resets(resets: usize) {
+    if resets > 0 {
+        eprintln!("Warning: movie resets the console {} times, which is ignored", resets);
+    }
+}
+
+fn import_vbm(data: &[u8], memory: &Memory) -> Result<Movie, String> {
+    if data.len() < VBM_HEADER_SIZE {
+        return Err("Movie ends early".to_string());
+    }
+
+    let start_flags = data[0x14];
+    let controllers = data[0x15] & VBM_CONTROLLERS;
+    if controllers & VBM_FIRST_CONTROLLER == 0 {
+        return Err("Movie has no input for the first controller".to_string());
+    }
+    let system = data[0x16];
+    let options = data[0x17];
+
+    // The flag marks a Game Boy Advance game, not a Game Boy game run on one
+    if system & VBM_SYSTEM_GBA != 0 {
+        return Err("Movie is for the Game Boy Advance, not the Game Boy".to_string());
+    }
+
+    let model = if system & VBM_SYSTEM_GBC != 0 {
+        Model::Cgb
+    }
+    else if system & VBM_SYSTEM_SGB != 0 {
+        Model::Sgb
+    }
+    else {
+        Model::Dmg
+    };
+
+    if options & VBM_OPTION_RTC != 0 {
+        eprintln!("Warning: movie was recorded with the real-time clock on");
+    }
+
+    // VBM only records the checksums from the cartridge header
+    let cartridge = memory.cartridge();
+    let header_checksum = cartridge.get(HEADER_CHECKSUM).copied();
+    let global_checksum = cartridge.get(GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+    if header_checksum != Some(data[0x31]) || global_checksum != Some(u16_at(data, 0x32)?) {
+        eprintln!("Warning: movie was recorded with a ROM with different header checksums");
+    }
+
+    let save_offset = u32_at(data, 0x38)? as usize;
+    let input_offset = u32_at(data, 0x3C)? as usize;
+
+    let start = if start_flags & VBM_START_STATE != 0 {
+        return Err("Movie starts from a VisualBoyAdvance save state, which can't be loaded".to_string());
+    }
+    else if start_flags & VBM_START_SAVE_RAM != 0 {
+        Start::SaveRam(data.get(save_offset..input_offset).ok_or("Movie ends early")?.to_vec())
+    }
+    else {
+        Start::PowerOn
+    };
+
+    // Each frame holds a 16-bit word per controller, only the first is used
+    // and it comes before the others
+    let stride = 2 * controllers.count_ones().max(1) as usize;
+    let count = u32_at(data, 0x0C)? as usize;
+    let mut frames = Vec::with_capacity(count);
+    let mut resets = 0;
+
+    for frame in 0..count {
+        let input = u16_at(data, input_offset + frame * stride)?;
+        if input & VBM_RESET != 0 {
+            resets += 1;
+        }
+
+        let buttons = VBM_BUTTONS.iter().enumerate()
+            .filter(|(bit, _)| input & (1 << bit) != 0)
+            .fold(0, |buttons, (_, button)| buttons | button);
+        frames.push(buttons);
+    }
+
+    warn_resets(resets);
+
+    Ok(Movie { rom_checksum: memory.rom_checksum(), model, boot_rom: false, start, frames })
+}
+
+fn bk2_text(data: &[u8], name: &str) -> Result<String, String> {
+    archive::zip_entry(data, name).map(|contents| String::from_utf8_lossy(&contents).into_owned())
+}
+
+fn import_bk2(data: &[u8], memory: &Memory) -> Result<Movie, String> {
+    let header = bk2_text(data, BK2_HEADER)?;
+    let value = |key: &str| header.lines().find_map(|line| {
+        line.split_once(' ').filter(|(name, _)| *name == key).map(|(_, value)| value.trim())
+    });
+    let flag = |key: &str| matches!(value(key), Some("True") | Some("true") | Some("1"));
+
+    let platform = value("Platform").unwrap_or("");
+    if !BK2_PLATFORMS.contains(&platform) {
+        return Err(format!("Movie is for {}, not the Game Boy", platform));
+    }
+
+    if flag("StartsFromSavestate") {
+        return Err("Movie starts from a BizHawk save state, which can't be loaded".to_string());
+    }
+
+    let start = if flag("StartsFromSaveRam") {
+        Start::SaveRam(archive::zip_entry(data, BK2_SAVE_RAM)?)
+    }
+    else {
+        Start::PowerOn
+    };
+
+    if let Some(hash) = value("SHA1") {
+        let digest: String = sha1(memory.cartridge()).iter().map(|byte| format!("{:02X}", byte)).collect();
+        if !hash.eq_ignore_ascii_case(&digest) {
+            eprintln!("Warning: movie was recorded with a different ROM, SHA1 {}", hash);
+        }
+    }
+
+    let model = if flag("IsCGBMode") || platform == "GBC" {
+        Model::Cgb
+    }
+    else if platform == "SGB" {
+        Model::Sgb
+    }
+    else {
+        Model::Dmg
+    };
+
+    // The boot ROM setting is the only one that matters here
+    let sync_settings: String = bk2_text(data, BK2_SYNC_SETTINGS)
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let boot_rom = sync_settings.contains("\"EnableBIOS\":true");
+
+    let input = bk2_text(data, BK2_INPUT)?;
+    let keys: Vec<String> = match input.lines().find_map(|line| line.strip_prefix("LogKey:")) {
+        Some(log_key) => log_key.trim_start_matches('#')
+            .split(['|', '#'])
+            .filter(|key| !key.is_empty())
+            .map(|key| key.trim_start_matches("P1 ").to_string())
+            .collect(),
+        None => BK2_DEFAULT_KEYS.iter().map(|key| key.to_string()).collect(),
+    };
+
+    let mut frames = Vec::new();
+    let mut resets = 0;
+
+    for line in input.lines().filter(|line| line.starts_with('|')) {
+        let mut buttons = 0;
+
+        // Each column is one key, a dot when it isn't pressed
+        let columns = line.chars().filter(|&c| c != '|');
+        for (key, column) in keys.iter().zip(columns) {
+            if column == '.' || column == ' ' {
+                continue;
+            }
+
+            buttons |= match key.as_str() {
+                "Up" => memory::JOYPAD_UP,
+                "Down" => memory::JOYPAD_DOWN,
+                "Left" => memory::JOYPAD_LEFT,
+                "Right" => memory::JOYPAD_RIGHT,
+                "Start" => memory::JOYPAD_START,
+                "Select" => memory::JOYPAD_SELECT,
+                "B" => memory::JOYPAD_B,
+                "A" => memory::JOYPAD_A,
+                "Power" => {
+                    resets += 1;
+                    0
+                }
+                _ => 0,
+            };
+        }
+        frames.push(buttons);
+    }
+
+    warn_resets(resets);
+
+    Ok(Movie { rom_checksum: memory.rom_checksum(), model, boot_rom, start, frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::{stored, zip};
+
+    // A power-on VBM movie for one controller holding the given frames
+    fn vbm(system: u8, frames: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; VBM_HEADER_SIZE];
+        data[..4].copy_from_slice(VBM_MAGIC);
+        data[0x0C..0x10].copy_from_slice(&(frames.len() as u32).to_le_bytes());
+        data[0x15] = 0x01;
+        data[0x16] = system;
+        data[0x3C..0x40].copy_from_slice(&(VBM_HEADER_SIZE as u32).to_le_bytes());
+        for frame in frames {
+            data.extend_from_slice(&frame.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn vbm_buttons_and_model() {
+        let movie = import(&vbm(VBM_SYSTEM_GBC, &[0x0001, 0x0090, 0x0000]), &Memory::new()).unwrap();
+
+        assert!(movie.model == Model::Cgb);
+        assert!(matches!(movie.start, Start::PowerOn));
+        assert_eq!(movie.frames, [memory::JOYPAD_A, memory::JOYPAD_RIGHT | memory::JOYPAD_DOWN, 0]);
+    }
+
+    #[test]
+    fn vbm_for_the_game_boy_advance_is_refused() {
+        assert!(import(&vbm(VBM_SYSTEM_GBA, &[]), &Memory::new()).is_err());
+    }
+
+    #[test]
+    fn vbm_without_the_first_controller_is_refused() {
+        let mut data = vbm(0, &[0x0001]);
+        data[0x15] = 0x02;
+
+        assert_eq!(import(&data, &Memory::new()).err().as_deref(), Some("Movie has no input for the first controller"));
+    }
+
+    #[test]
+    fn bk2_with_a_log_key() {
+        let header = b"MovieVersion BizHawk v2.0.0\nPlatform GBC\nStartsFromSaveRam True\n";
+        let input = b"[Input]\nLogKey:#Reset|#P1 A|P1 B|P1 Up|\n|.|A.U|\n|.|...|\n|r|.B.|\n[/Input]\n";
+        let data = zip(&[
+            stored(BK2_HEADER, header),
+            stored(BK2_INPUT, input),
+            stored(BK2_SYNC_SETTINGS, b"{ \"EnableBIOS\": true }"),
+            stored(BK2_SAVE_RAM, &[0x12, 0x34]),
+        ]);
+        let movie = import(&data, &Memory::new()).unwrap();
+
+        assert!(movie.model == Model::Cgb);
+        assert!(movie.boot_rom);
+        assert!(matches!(movie.start, Start::SaveRam(ref ram) if ram[..] == [0x12, 0x34]));
+        assert_eq!(movie.frames, [memory::JOYPAD_A | memory::JOYPAD_UP, 0, memory::JOYPAD_B]);
+    }
+
+    #[test]
+    fn bk2_in_gambatte_order() {
+        let header = b"Platform SGB\n";
+        let input = b"[Input]\n|U......AP|\n|.D..S....|\n[/Input]\n";
+        let movie = import(&zip(&[stored(BK2_HEADER, header), stored(BK2_INPUT, input)]), &Memory::new()).unwrap();
+
+        assert!(movie.model == Model::Sgb);
+        assert!(!movie.boot_rom);
+        assert!(matches!(movie.start, Start::PowerOn));
+        // Power resets the console and isn't a button
+        assert_eq!(movie.frames, [memory::JOYPAD_UP | memory::JOYPAD_A, memory::JOYPAD_DOWN | memory::JOYPAD_START]);
+    }
+
+    #[test]
+    fn bk2_for_other_systems_is_refused() {
+        let data = zip(&[stored(BK2_HEADER, b"Platform NES\n"), stored(BK2_INPUT, b"")]);
+        assert_eq!(import(&data, &Memory::new()).err().as_deref(), Some("Movie is for NES, not the Game Boy"));
+
+        let data = zip(&[stored(BK2_HEADER, b"Platform GB\nStartsFromSavestate True\n"), stored(BK2_INPUT, b"")]);
+        assert!(import(&data, &Memory::new()).is_err());
+    }
+}
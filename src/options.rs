@@ -0,0 +1,327 @@
+use crate::compatibility;
+use crate::model::Model;
+use crate::speed::FastForward;
+use crate::symbols::Symbols;
+use crate::trace::TraceFilter;
+
+const DEFAULT_ROM_PATH: &str = "roms/boot-rom.gb";
+const DEFAULT_FAST_FORWARD: u32 = 4;
+const DEFAULT_REWIND_INTERVAL: u32 = 2;
+const DEFAULT_REWIND_BUFFER_MB: usize = 64;
+
+pub enum LinkMode {
+    Host(u16),
+    Client(u16),
+}
+
+pub struct Options {
+    pub rom_path: String,
+    pub boot_rom: Option<String>,
+    // Found next to the ROM when not given
+    pub patch: Option<String>,
+    pub zip_entry: Option<String>,
+    // Chosen from the cartridge header when not given
+    pub model: Option<Model>,
+    pub cgb_palette: Option<usize>,
+    pub fast_forward: FastForward,
+    pub rewind_interval: u32,
+    pub rewind_buffer_size: usize,
+    pub serial_out: Option<String>,
+    pub link: Option<LinkMode>,
+    pub printer: Option<String>,
+    pub headless: bool,
+    pub tile_viewer: bool,
+    pub map_viewer: bool,
+    pub oam_viewer: bool,
+    pub memory_viewer: bool,
+    pub frames: Option<u32>,
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub ram_search: bool,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub read_write: bool,
+    // Recording starts from this save state rather than power-on
+    pub movie_state: Option<String>,
+    // From the .sym file next to the ROM
+    pub symbols: Symbols,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            rom_path: DEFAULT_ROM_PATH.to_string(),
+            boot_rom: None,
+            patch: None,
+            zip_entry: None,
+            model: None,
+            cgb_palette: None,
+            fast_forward: FastForward::Multiplier(DEFAULT_FAST_FORWARD),
+            rewind_interval: DEFAULT_REWIND_INTERVAL,
+            rewind_buffer_size: DEFAULT_REWIND_BUFFER_MB * 1024 * 1024,
+            serial_out: None,
+            link: None,
+            printer: None,
+            headless: false,
+            tile_viewer: false,
+            map_viewer: false,
+            oam_viewer: false,
+            memory_viewer: false,
+            frames: None,
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            debug: false,
+            gdb: None,
+            ram_search: false,
+            record: None,
+            play: None,
+            read_write: false,
+            movie_state: None,
+            symbols: Symbols::default(),
+        };
+
+        // Addresses can be labels, which are looked up once the ROM is known
+        let mut trace_range = None;
+        let mut trace_start = None;
+        let mut trace_stop = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--boot-rom" => options.boot_rom = Some(next_value(&mut args, &arg)?),
+                "--patch" => options.patch = Some(next_value(&mut args, &arg)?),
+                "--zip-entry" => options.zip_entry = Some(next_value(&mut args, &arg)?),
+                "--model" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let model = Model::parse(&value).ok_or_else(|| {
+                        format!("Unknown model {}, expected one of DMG0, DMG, MGB, SGB, SGB2, CGB or AGB", value)
+                    })?;
+                    options.model = Some(model);
+                }
+                "--cgb-palette" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.cgb_palette = Some(parse_palette_combination(&value)?);
+                }
+                "--ff-speed" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.fast_forward = parse_fast_forward(&value)?;
+                }
+                "--rewind-interval" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.rewind_interval = parse_number(&value, &arg)?;
+                    if options.rewind_interval == 0 {
+                        return Err("Option --rewind-interval needs at least 1 frame".to_string());
+                    }
+                }
+                "--rewind-buffer" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let megabytes: usize = parse_number(&value, &arg)?;
+                    options.rewind_buffer_size = megabytes * 1024 * 1024;
+                }
+                "--serial-out" => options.serial_out = Some(next_value(&mut args, &arg)?),
+                "--link-host" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.link = Some(LinkMode::Host(parse_number(&value, &arg)?));
+                }
+                "--link-connect" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.link = Some(LinkMode::Client(parse_number(&value, &arg)?));
+                }
+                "--printer" => options.printer = Some(next_value(&mut args, &arg)?),
+                "--headless" => options.headless = true,
+                "--tile-viewer" => options.tile_viewer = true,
+                "--map-viewer" => options.map_viewer = true,
+                "--oam-viewer" => options.oam_viewer = true,
+                "--memory-viewer" => options.memory_viewer = true,
+                "--frames" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.frames = Some(parse_number(&value, &arg)?);
+                }
+                "--trace" => options.trace = Some(next_value(&mut args, &arg)?),
+                "--trace-range" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let (start, end) = value.split_once('-')
+                        .ok_or_else(|| format!("Invalid range {} for option {}, expected START-END", value, arg))?;
+                    trace_range = Some((start.to_string(), end.to_string()));
+                }
+                "--trace-bank" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.trace_filter.bank = Some(parse_number(&value, &arg)?);
+                }
+                "--trace-start" => trace_start = Some(next_value(&mut args, &arg)?),
+                "--trace-stop" => trace_stop = Some(next_value(&mut args, &arg)?),
+                "--trace-disasm" => options.trace_filter.disassemble = true,
+                "--trace-labels" => options.trace_filter.labels = true,
+                "--debug" => options.debug = true,
+                "--gdb" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.gdb = Some(parse_number(&value, &arg)?);
+                }
+                "--ram-search" => options.ram_search = true,
+                "--record" => options.record = Some(next_value(&mut args, &arg)?),
+                "--play" => options.play = Some(next_value(&mut args, &arg)?),
+                "--read-write" => options.read_write = true,
+                "--movie-state" => options.movie_state = Some(next_value(&mut args, &arg)?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => options.rom_path = arg,
+            }
+        }
+
+        if options.link.is_some() && options.printer.is_some() {
+            return Err("Only one device can be connected to the serial port".to_string());
+        }
+
+        options.symbols = Symbols::next_to(&options.rom_path);
+
+        if let Some((start, end)) = trace_range {
+            let symbols = &options.symbols;
+            options.trace_filter.range = Some((
+                parse_address(symbols, &start, "--trace-range")?,
+                parse_address(symbols, &end, "--trace-range")?
+            ));
+        }
+
+        if let Some(start) = trace_start {
+            options.trace_filter.start = Some(parse_address(&options.symbols, &start, "--trace-start")?);
+        }
+
+        if let Some(stop) = trace_stop {
+            options.trace_filter.stop = Some(parse_address(&options.symbols, &stop, "--trace-stop")?);
+        }
+
+        if options.headless && (options.tile_viewer || options.map_viewer || options.oam_viewer || options.memory_viewer) {
+            return Err("Viewer windows can't be opened with --headless".to_string());
+        }
+
+        if options.debug && options.gdb.is_some() {
+            return Err("Only one of --debug and --gdb can be used".to_string());
+        }
+
+        if options.debug && options.ram_search {
+            return Err("Only one of --debug and --ram-search can read the terminal".to_string());
+        }
+
+        if options.cgb_palette.is_some() && !options.model.is_some_and(|model| model.is_cgb()) {
+            return Err("Option --cgb-palette needs --model CGB or AGB".to_string());
+        }
+
+        if options.trace_filter.is_set() && options.trace.is_none() {
+            return Err("Trace filters need --trace".to_string());
+        }
+
+        if options.record.is_some() && options.play.is_some() {
+            return Err("Only one of --record and --play can be used".to_string());
+        }
+
+        if options.read_write && options.play.is_none() {
+            return Err("Option --read-write needs --play".to_string());
+        }
+
+        if options.movie_state.is_some() && options.record.is_none() {
+            return Err("Option --movie-state needs --record".to_string());
+        }
+
+        Ok(options)
+    }
+}
+
+// Options for `dustboy disasm rom.gb [--zip-entry NAME] [--bank N] [--from ADDR] [--count N]`
+pub struct DisasmOptions {
+    pub rom_path: String,
+    pub zip_entry: Option<String>,
+    pub bank: usize,
+    pub from: Option<u16>,
+    pub count: Option<usize>,
+    pub symbols: Symbols,
+}
+
+impl DisasmOptions {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<DisasmOptions, String> {
+        let mut rom_path = None;
+        let mut bank = None;
+        let mut from = None;
+        let mut options = DisasmOptions {
+            rom_path: String::new(),
+            zip_entry: None,
+            bank: 0,
+            from: None,
+            count: None,
+            symbols: Symbols::default(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--zip-entry" => options.zip_entry = Some(next_value(&mut args, &arg)?),
+                "--bank" => {
+                    let value = next_value(&mut args, &arg)?;
+                    bank = Some(parse_number(&value, &arg)?);
+                }
+                "--from" => from = Some(next_value(&mut args, &arg)?),
+                "--count" => {
+                    let value = next_value(&mut args, &arg)?;
+                    options.count = Some(parse_number(&value, &arg)?);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => rom_path = Some(arg),
+            }
+        }
+
+        options.rom_path = rom_path.ok_or_else(|| "disasm expects a ROM to disassemble".to_string())?;
+        options.symbols = Symbols::next_to(&options.rom_path);
+
+        if let Some(from) = from {
+            options.from = Some(parse_address(&options.symbols, &from, "--from")?);
+
+            // Starting at a label shows the bank it was placed in
+            if let Some((label_bank, _)) = options.symbols.resolve(&from) {
+                bank = bank.or(Some(label_bank));
+            }
+        }
+
+        options.bank = bank.unwrap_or(0);
+        Ok(options)
+    }
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("Option {} expects a value", option))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, option: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value {} for option {}", value, option))
+}
+
+fn parse_address(symbols: &Symbols, value: &str, option: &str) -> Result<u16, String> {
+    symbols.parse_address(value).ok_or_else(|| format!("Invalid address or unknown label {} for option {}", value, option))
+}
+
+// Addresses are hexadecimal, with or without a $ or 0x prefix.
+pub fn parse_hex(value: &str) -> Option<u16> {
+    let digits = value.strip_prefix('$')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value);
+
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_fast_forward(value: &str) -> Result<FastForward, String> {
+    if value == "uncapped" {
+        return Ok(FastForward::Uncapped);
+    }
+
+    match value.parse::<u32>() {
+        Ok(multiplier) if multiplier >= 1 => Ok(FastForward::Multiplier(multiplier)),
+        _ => Err(format!("Invalid fast-forward speed {}, expected a multiplier or 'uncapped'", value))
+    }
+}
+
+fn parse_palette_combination(value: &str) -> Result<usize, String> {
+    compatibility::parse_combination(value).ok_or_else(|| {
+        format!(
+            "Invalid palette combination {}, expected a button combination such as 'left-b' or a number below {}",
+            value,
+            compatibility::COMBINATION_COUNT
+        )
+    })
+}
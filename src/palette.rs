@@ -0,0 +1,78 @@
+use crate::state::{StateReader, StateWriter};
+
+const PALETTE_RAM_SIZE: usize = 64;
+const AUTO_INCREMENT: u8 = 0x80;
+const INDEX_MASK: u8 = 0x3F;
+
+// CGB colour palette RAM, 8 palettes of 4 colours stored as little endian
+// 15-bit RGB. Accessed through a specification register (BCPS/OCPS) holding
+// the index and auto-increment flag, and a data register (BCPD/OCPD).
+pub struct ColourPalettes {
+    specification: u8,
+    data: [u8; PALETTE_RAM_SIZE],
+}
+
+impl ColourPalettes {
+    pub fn new() -> Self {
+        ColourPalettes {
+            specification: 0,
+            data: [0xFF; PALETTE_RAM_SIZE],
+        }
+    }
+
+    pub fn read_specification(&self) -> u8 {
+        // Bit 6 is unused and reads back as set
+        self.specification | 0x40
+    }
+
+    pub fn write_specification(&mut self, value: u8) {
+        self.specification = value & (AUTO_INCREMENT | INDEX_MASK);
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[(self.specification & INDEX_MASK) as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        let index = self.specification & INDEX_MASK;
+        self.data[index as usize] = value;
+
+        if self.specification & AUTO_INCREMENT != 0 {
+            self.specification = AUTO_INCREMENT | ((index + 1) & INDEX_MASK);
+        }
+    }
+
+    pub fn colour(&self, palette: u8, colour: u8) -> (u8, u8, u8) {
+        let index = (palette as usize * 4 + colour as usize) * 2;
+        let value = self.data[index] as u16 | (self.data[index + 1] as u16) << 8;
+
+        rgb555_to_rgb888(value)
+    }
+
+    pub fn set_colour(&mut self, palette: u8, colour: u8, value: u16) {
+        let index = (palette as usize * 4 + colour as usize) * 2;
+        self.data[index] = value as u8;
+        self.data[index + 1] = (value >> 8) as u8;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.specification);
+        writer.write_bytes(&self.data);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.specification = reader.read_u8()?;
+        self.data.copy_from_slice(reader.read_bytes(PALETTE_RAM_SIZE)?);
+
+        Ok(())
+    }
+}
+
+pub fn rgb555_to_rgb888(value: u16) -> (u8, u8, u8) {
+    let scale = |component: u16| {
+        let component = (component & 0x1F) as u8;
+        (component << 3) | (component >> 2)
+    };
+
+    (scale(value), scale(value >> 5), scale(value >> 10))
+}
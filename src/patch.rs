@@ -0,0 +1,406 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const UPS_MAGIC: &[u8] = b"UPS1";
+// Source, target and patch CRC32s
+const FOOTER_SIZE: usize = 12;
+// The largest cartridge ROM, patches can't make one bigger
+const MAX_ROM_SIZE: usize = 0x80_0000;
+const EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+const BPS_SOURCE_COPY: u64 = 2;
+
+// Finds game.ips, game.bps or game.ups for game.gb.
+pub fn next_to(rom_path: &str) -> Option<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|extension| Path::new(rom_path).with_extension(extension))
+        .find(|path| path.exists())
+}
+
+pub fn load(rom: &[u8], patch_path: &Path) -> Result<Vec<u8>, String> {
+    let patch = fs::read(patch_path).map_err(|e| e.to_string())?;
+    apply(rom, &patch).map_err(|err| format!("{}: {}", patch_path.display(), err))
+}
+
+// Returns the patched ROM, recognising the format from the patch header.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, &patch[IPS_MAGIC.len()..])
+    }
+    else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    }
+    else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    }
+    else {
+        Err("Not an IPS, BPS or UPS patch".to_string())
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Self {
+        PatchReader { data, position }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self.position.checked_add(count)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or("Patch ends early")?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, count: usize) -> Result<usize, String> {
+        Ok(self.bytes(count)?.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    // BPS and UPS numbers store 7 bits a byte, with each continuation also
+    // adding one so that every value has a single encoding.
+    fn number(&mut self) -> Result<u64, String> {
+        let too_large = || "Number in patch is too large".to_string();
+        let mut value: u64 = 0;
+        let mut shift: u64 = 1;
+
+        loop {
+            let byte = self.byte()?;
+            value = ((byte & 0x7F) as u64).checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or_else(too_large)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(too_large)?;
+            value = value.checked_add(shift).ok_or_else(too_large)?;
+        }
+    }
+
+    fn size(&mut self) -> Result<usize, String> {
+        usize::try_from(self.number()?).map_err(|_| "Size in patch is too large".to_string())
+    }
+
+    // The size of the patched ROM, which is allocated up front.
+    fn target_size(&mut self) -> Result<usize, String> {
+        let size = self.size()?;
+        if size > MAX_ROM_SIZE {
+            return Err(format!("Patched ROM would be {} bytes, more than the largest cartridge", size));
+        }
+        Ok(size)
+    }
+}
+
+// Records of a 3-byte offset and 2-byte length followed by the data, or a
+// zero length, a 2-byte count and a byte to repeat. An optional 3-byte
+// size after EOF truncates the ROM.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut target = rom.to_vec();
+    let mut reader = PatchReader::new(patch, 0);
+
+    loop {
+        if reader.bytes(IPS_EOF.len())? == IPS_EOF {
+            break;
+        }
+        reader.position -= IPS_EOF.len();
+
+        let offset = reader.big_endian(3)?;
+        let length = reader.big_endian(2)?;
+
+        let (length, data) = if length == 0 {
+            let count = reader.big_endian(2)?;
+            (count, vec![reader.byte()?; count])
+        }
+        else {
+            (length, reader.bytes(length)?.to_vec())
+        };
+
+        if target.len() < offset + length {
+            target.resize(offset + length, 0);
+        }
+        target[offset..offset + length].copy_from_slice(&data);
+    }
+
+    if let Ok(size) = reader.big_endian(3) {
+        target.truncate(size);
+    }
+
+    Ok(target)
+}
+
+// Checks the CRC32s at the end of a BPS or UPS patch and returns where the
+// footer starts.
+fn check_footer(source: &[u8], patch: &[u8]) -> Result<(usize, u32), String> {
+    if patch.len() < FOOTER_SIZE {
+        return Err("Patch ends early".to_string());
+    }
+
+    let footer = patch.len() - FOOTER_SIZE;
+    let mut reader = PatchReader::new(patch, footer);
+    let mut crc = || reader.bytes(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    let (source_crc, target_crc, patch_crc) = (crc()?, crc()?, crc()?);
+
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err("Patch is corrupt, its checksum doesn't match".to_string());
+    }
+
+    if crc32(source) != source_crc {
+        return Err(format!("Patch is for a different ROM, expected CRC32 {:08X} but found {:08X}", source_crc, crc32(source)));
+    }
+
+    Ok((footer, target_crc))
+}
+
+fn check_target(target: &[u8], expected_size: usize, expected_crc: u32) -> Result<(), String> {
+    if target.len() != expected_size || crc32(target) != expected_crc {
+        return Err("Patched ROM doesn't match the checksum in the patch".to_string());
+    }
+
+    Ok(())
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (footer, target_crc) = check_footer(source, patch)?;
+    let mut reader = PatchReader::new(&patch[..footer], BPS_MAGIC.len());
+
+    if reader.size()? != source.len() {
+        return Err("Patch is for a ROM of a different size".to_string());
+    }
+    let target_size = reader.target_size()?;
+    let metadata_size = reader.size()?;
+    reader.bytes(metadata_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while reader.position < footer {
+        let command = reader.number()?;
+        let length = (command >> 2) as usize + 1;
+        // Target copies can repeat what they write, so they'd never run out
+        if length > target_size - target.len() {
+            return Err("Patch writes past the end of the ROM".to_string());
+        }
+
+        match command & 3 {
+            BPS_SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..start + length).ok_or("Patch reads past the end of the ROM")?);
+            }
+            BPS_TARGET_READ => target.extend_from_slice(reader.bytes(length)?),
+            command => {
+                // Copies move a cursor by a signed offset, the low bit is the sign
+                let offset = reader.number()?;
+                let cursor = if command == BPS_SOURCE_COPY { &mut source_offset } else { &mut target_offset };
+                let distance = (offset >> 1) as usize;
+                *cursor = if offset & 1 != 0 { cursor.checked_sub(distance) } else { cursor.checked_add(distance) }
+                    .ok_or("Patch copies from outside the ROM")?;
+
+                for _ in 0..length {
+                    let byte = if command == BPS_SOURCE_COPY { source.get(*cursor) } else { target.get(*cursor) };
+                    target.push(*byte.ok_or("Patch copies from outside the ROM")?);
+                    *cursor += 1;
+                }
+            }
+        }
+    }
+
+    check_target(&target, target_size, target_crc)?;
+    Ok(target)
+}
+
+// Runs of bytes XORed with the source, each ended by a zero and preceded
+// by how many bytes to skip.
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (footer, target_crc) = check_footer(source, patch)?;
+    let mut reader = PatchReader::new(&patch[..footer], UPS_MAGIC.len());
+
+    if reader.size()? != source.len() {
+        return Err("Patch is for a ROM of a different size".to_string());
+    }
+    let target_size = reader.target_size()?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut offset: usize = 0;
+
+    while reader.position < footer {
+        offset = offset.checked_add(reader.size()?).ok_or("Patch writes past the end of the ROM")?;
+
+        loop {
+            let byte = reader.byte()?;
+            if byte == 0 {
+                offset += 1;
+                break;
+            }
+
+            *target.get_mut(offset).ok_or("Patch writes past the end of the ROM")? ^= byte;
+            offset += 1;
+        }
+    }
+
+    check_target(&target, target_size, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &[u8] = b"Hello, world!";
+
+    fn number(output: &mut Vec<u8>, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                output.push(byte | 0x80);
+                return;
+            }
+            output.push(byte);
+            value -= 1;
+        }
+    }
+
+    // Adds the source, target and patch CRC32s to a BPS or UPS patch.
+    fn finish(mut patch: Vec<u8>, source: &[u8], target_crc: u32) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&target_crc.to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn bps(target: &[u8], target_crc: u32) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        number(&mut patch, SOURCE.len() as u64);
+        number(&mut patch, target.len() as u64);
+        number(&mut patch, 0);
+
+        // "Hello, world!" from the source, then " " and a copy of "world"
+        number(&mut patch, (13 - 1) << 2 | BPS_SOURCE_READ);
+        number(&mut patch, BPS_TARGET_READ);
+        patch.push(b' ');
+        number(&mut patch, (5 - 1) << 2 | BPS_SOURCE_COPY);
+        number(&mut patch, 7 << 1);
+
+        finish(patch, SOURCE, target_crc)
+    }
+
+    fn ups(target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        number(&mut patch, SOURCE.len() as u64);
+        number(&mut patch, target.len() as u64);
+        number(&mut patch, 7);
+        patch.extend_from_slice(&[b'w' ^ b'W', 0]);
+
+        finish(patch, SOURCE, crc32(target))
+    }
+
+    #[test]
+    fn ips_records_fill_and_truncation() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x07, 0x00, 0x05]);
+        patch.extend_from_slice(b"World");
+        // A run of three dots past the end grows the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x0D, 0x00, 0x00, 0x00, 0x03, b'.']);
+        patch.extend_from_slice(IPS_EOF);
+        assert_eq!(apply(SOURCE, &patch).unwrap(), b"Hello, World!...");
+
+        patch.extend_from_slice(&[0x00, 0x00, 0x05]);
+        assert_eq!(apply(SOURCE, &patch).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn ips_without_eof_is_refused() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x07, 0x00, 0x05]);
+        patch.extend_from_slice(b"Wor");
+        assert!(apply(SOURCE, &patch).is_err());
+    }
+
+    #[test]
+    fn bps_reads_and_copies() {
+        let target = b"Hello, world! world";
+        assert_eq!(apply(SOURCE, &bps(target, crc32(target))).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_xors_changes() {
+        let target = b"Hello, World!";
+        assert_eq!(apply(SOURCE, &ups(target)).unwrap(), target);
+    }
+
+    #[test]
+    fn checksum_failures() {
+        let target = b"Hello, world! world";
+        let patch = bps(target, crc32(target));
+
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 0x01;
+        assert_eq!(apply(SOURCE, &corrupt).unwrap_err(), "Patch is corrupt, its checksum doesn't match");
+
+        assert!(apply(b"Hello, World!", &patch).unwrap_err().starts_with("Patch is for a different ROM"));
+        assert!(apply(b"Hello, World!", &ups(b"Hello, World!")).unwrap_err().starts_with("Patch is for a different ROM"));
+
+        assert_eq!(apply(SOURCE, &bps(target, 0)).unwrap_err(), "Patched ROM doesn't match the checksum in the patch");
+    }
+
+    #[test]
+    fn overlong_numbers_are_refused() {
+        for bytes in [[0x00; 12], [0x7F; 12]] {
+            let mut patch = BPS_MAGIC.to_vec();
+            patch.extend_from_slice(&bytes);
+            patch.push(0x80);
+
+            assert_eq!(apply(SOURCE, &finish(patch, SOURCE, 0)).unwrap_err(), "Number in patch is too large");
+        }
+    }
+
+    #[test]
+    fn oversized_targets_are_refused() {
+        for magic in [BPS_MAGIC, UPS_MAGIC] {
+            let mut patch = magic.to_vec();
+            number(&mut patch, SOURCE.len() as u64);
+            number(&mut patch, 1 << 40);
+            number(&mut patch, 0);
+
+            assert!(apply(SOURCE, &finish(patch, SOURCE, 0)).unwrap_err().starts_with("Patched ROM would be"));
+        }
+    }
+
+    #[test]
+    fn bps_copies_stop_at_the_target_size() {
+        let mut patch = BPS_MAGIC.to_vec();
+        number(&mut patch, SOURCE.len() as u64);
+        number(&mut patch, 2);
+        number(&mut patch, 0);
+
+        // One byte, then a target copy of itself that would go on for ever
+        number(&mut patch, BPS_TARGET_READ);
+        patch.push(b'!');
+        number(&mut patch, (1 << 40) << 2 | 3);
+        number(&mut patch, 0);
+
+        assert_eq!(apply(SOURCE, &finish(patch, SOURCE, 0)).unwrap_err(), "Patch writes past the end of the ROM");
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert_eq!(apply(SOURCE, b"NOTAPATCH").unwrap_err(), "Not an IPS, BPS or UPS patch");
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::Path;
+
+use crate::checksum::{adler32, crc32};
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const GREYSCALE: u8 = 0;
+
+// Largest amount of data a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+pub fn write_greyscale(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<(), String> {
+    write_png(path, width, height, GREYSCALE, 1, pixels)
+}
+
+fn write_png(path: &Path, width: u32, height: u32, colour_type: u8,
+             bytes_per_pixel: usize, pixels: &[u8]) -> Result<(), String> {
+    let stride = width as usize * bytes_per_pixel;
+    assert_eq!(pixels.len(), stride * height as usize);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, no interlacing, default compression and filter
+    header.extend_from_slice(&[8, colour_type, 0, 0, 0]);
+
+    // Each scanline is prefixed with filter type 0 (none)
+    let mut image = Vec::with_capacity((stride + 1) * height as usize);
+    for line in pixels.chunks(stride) {
+        image.push(0);
+        image.extend_from_slice(line);
+    }
+
+    let mut png = Vec::new();
+    png.extend_from_slice(SIGNATURE);
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&image));
+    write_chunk(&mut png, b"IEND", &[]);
+
+    fs::write(path, png).map_err(|e| e.to_string())
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let mut chunk = Vec::with_capacity(data.len() + 4);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+
+    png.extend_from_slice(&chunk);
+    png.extend_from_slice(&crc32(&chunk).to_be_bytes());
+}
+
+// Wraps the data in a zlib stream made of uncompressed deflate blocks, the
+// images are small enough that compressing them isn't worth the code.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+
+        output.push(last as u8);
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
@@ -1,24 +1,55 @@
 extern crate sdl2;
 
 use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 
 use crate::memory::*;
+use crate::sgb;
+use crate::state::{StateReader, StateWriter};
 
-const CONTROL_REG: usize = 0xFF40;
+pub const CONTROL_REG: usize = 0xFF40;
 const STATUS_REG: usize = 0xFF41;
-const COLOUR_ADDR: usize = 0xFF47;
+pub const SCY: usize = 0xFF42;
+pub const SCX: usize = 0xFF43;
+pub const COLOUR_ADDR: usize = 0xFF47;
+pub const OBP0: usize = 0xFF48;
+pub const OBP1: usize = 0xFF49;
+pub const WY: usize = 0xFF4A;
+pub const WX: usize = 0xFF4B;
+
+pub const OAM_ADDR: usize = 0xFE00;
+pub const OAM_ENTRIES: usize = 40;
+const SPRITES_PER_LINE: usize = 10;
+
+pub const TILE_MAP_LOW: usize = 0x9800;
+pub const TILE_MAP_HIGH: usize = 0x9C00;
+pub const TILE_DATA_UNSIGNED: usize = 0x8000;
+pub const TILE_DATA_SIGNED: usize = 0x9000;
+
+// Attribute bits shared by CGB background map attributes and OAM flags
+pub const ATTR_PALETTE: u8 = 0x07;
+pub const ATTR_BANK: u8 = 0x08;
+pub const ATTR_DMG_PALETTE: u8 = 0x10;
+pub const ATTR_X_FLIP: u8 = 0x20;
+pub const ATTR_Y_FLIP: u8 = 0x40;
+const ATTR_PRIORITY: u8 = 0x80;
 
 const BG_WIDTH: u32 = 256;
 const BG_HEIGHT: u32 = 256;
 
-const SCREEN_WIDTH: u32 = 160;
-const SCREEN_HEIGHT: u32 = 144;
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
 
 const LY : usize = 0xFF44;
 
+const DOTS_PER_LINE: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SEARCH_DOTS: u32 = 80;
+const PIXEL_TRANSFER_DOTS: u32 = 172;
+
 enum ModeFlag {
     HBLANK,
     VBLANK,
@@ -78,8 +109,28 @@ impl LCDStatusRegister {
     }
 }
 
+#[derive(Clone, Copy)]
+struct BackgroundPixel {
+    colour: u8,
+    priority: bool,
+}
+
+struct Sprite {
+    x: i16,
+    y: i16,
+    tile: u8,
+    attributes: u8,
+}
+
 pub struct PPU {
-    canvas: Canvas<Window>,
+    canvas: Option<Canvas<Window>>,
+    frame: Vec<u8>,
+    // Monochrome shade of each pixel, which the SGB colours itself
+    shades: Vec<u8>,
+    // The SGB picture with its border, when running as one
+    sgb_frame: Option<Vec<u8>>,
+    dot: u32,
+    window_line: u8,
     control_reg: ControlRegister,
     lcd_stat_reg: LCDStatusRegister,
     colour_zero: pixels::Color,
@@ -89,18 +140,27 @@ pub struct PPU {
 }
 
 impl PPU {
-    pub fn new() -> Self {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem.window("dustboy", SCREEN_WIDTH, SCREEN_HEIGHT)
-                                    .position_centered()
-                                    .build()
-                                    .unwrap();
+    // Without an SDL context the PPU runs headless and nothing is displayed.
+    pub fn new(sdl_context: Option<&sdl2::Sdl>, sgb: bool) -> Self {
+        let (width, height) = output_size(sgb);
+
+        let canvas = sdl_context.map(|sdl_context| {
+            let video_subsystem = sdl_context.video().unwrap();
+            let window = video_subsystem.window("dustboy", width, height)
+                                        .position_centered()
+                                        .build()
+                                        .unwrap();
 
-        let canvas = window.into_canvas().build().unwrap();
+            window.into_canvas().build().unwrap()
+        });
 
         PPU { 
               canvas,
+              frame: vec![0xFF; (SCREEN_WIDTH * SCREEN_HEIGHT * 3) as usize],
+              shades: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+              sgb_frame: sgb.then(|| vec![0; sgb::SCREEN_WIDTH * sgb::SCREEN_HEIGHT * 3]),
+              dot: 0,
+              window_line: 0,
               control_reg: ControlRegister::new(),
               lcd_stat_reg: LCDStatusRegister::new(),
               colour_zero: pixels::Color::RGB(0, 0, 0),
@@ -110,13 +170,129 @@ impl PPU {
             }
     }
 
+    // Advances the PPU by the given number of cycles, returns true once a
+    // frame has been completed and the PPU has entered VBlank.
+    pub fn step(&mut self, ticks: u32, memory_bus: &mut Memory) -> bool {
+        let mut frame_complete = false;
+        self.dot += ticks;
+
+        while self.dot >= DOTS_PER_LINE {
+            self.dot -= DOTS_PER_LINE;
+
+            let finished_line = memory_bus.read_internal(LY);
+            if finished_line < SCREEN_HEIGHT as u8 {
+                self.render_line(finished_line, memory_bus);
+            }
+
+            let line = (finished_line + 1) % LINES_PER_FRAME;
+            memory_bus.write_internal(LY, line);
+
+            if line == 0 {
+                self.window_line = 0;
+            }
+
+            if line == SCREEN_HEIGHT as u8 {
+                self.render_sgb(memory_bus);
+                frame_complete = true;
+            }
+        }
+
+        self.update_mode(memory_bus);
+
+        frame_complete
+    }
+
+    // Mode, LY and the LCD registers live in memory, so only the position
+    // within the current line and the window's next row need saving.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.dot);
+        writer.write_u8(self.window_line);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.dot = reader.read_u32()?;
+        self.window_line = reader.read_u8()?;
+
+        Ok(())
+    }
+
+    // Redraws the whole frame from the current state of memory, used when
+    // the state has been replaced rather than emulated up to.
     pub fn render(&mut self,  memory_bus: &mut Memory) {
-        self.set_color_palette(memory_bus.read_memory(COLOUR_ADDR));
-        self.set_control_registers(memory_bus.read_memory(CONTROL_REG));
-        self.set_lcd_stat_registers(memory_bus.read_memory(STATUS_REG));
-        self.render_background(memory_bus);
+        // The window row reached mid-frame is kept for the lines still to come
+        let window_line = self.window_line;
+        self.window_line = 0;
 
-        memory_bus.write_memory(LY, 0x90);
+        for line in 0..SCREEN_HEIGHT as u8 {
+            self.render_line(line, memory_bus);
+        }
+        self.render_sgb(memory_bus);
+
+        self.window_line = window_line;
+    }
+
+    fn render_sgb(&mut self, memory_bus: &Memory) {
+        if let (Some(frame), Some(sgb)) = (self.sgb_frame.as_mut(), memory_bus.sgb()) {
+            sgb.render(&self.shades, frame);
+        }
+    }
+
+    fn render_line(&mut self, line: u8, memory_bus: &Memory) {
+        self.set_color_palette(memory_bus.read_internal(COLOUR_ADDR));
+        self.set_control_registers(memory_bus.read_internal(CONTROL_REG));
+        self.set_lcd_stat_registers(memory_bus.read_internal(STATUS_REG));
+
+        let mut background = [BackgroundPixel { colour: 0, priority: false }; SCREEN_WIDTH as usize];
+
+        if !self.control_reg.lcd_enable {
+            for x in 0..SCREEN_WIDTH as usize {
+                self.set_pixel(x, line, colour(0));
+                self.set_shade(x, line, 0);
+            }
+            return;
+        }
+
+        self.render_background(line, memory_bus, &mut background);
+
+        if self.control_reg.sprite_enable {
+            self.render_sprites(line, memory_bus, &background);
+        }
+    }
+
+    fn update_mode(&mut self, memory_bus: &mut Memory) {
+        let line = memory_bus.read_internal(LY);
+
+        let mode = if line >= SCREEN_HEIGHT as u8 {
+            ModeFlag::VBLANK
+        }
+        else if self.dot < OAM_SEARCH_DOTS {
+            ModeFlag::OAMRAM
+        }
+        else if self.dot < OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS {
+            ModeFlag::DATATOLCD
+        }
+        else {
+            ModeFlag::HBLANK
+        };
+
+        let mode_bits = match mode {
+            ModeFlag::HBLANK => 0x00,
+            ModeFlag::VBLANK => 0x01,
+            ModeFlag::OAMRAM => 0x02,
+            ModeFlag::DATATOLCD => 0x03,
+        };
+
+        // Two OAM entries are read every 4 dots
+        let scan_row = if mode_bits == 0x02 { Some((self.dot / 4) as usize) } else { None };
+        memory_bus.set_oam_scan_row(scan_row);
+
+        let status = memory_bus.read_internal(STATUS_REG);
+        memory_bus.write_internal(STATUS_REG, (status & !0x03) | mode_bits);
+        self.lcd_stat_reg.mode_flag = mode;
+
+        if mode_bits == 0x00 && status & 0x03 != 0x00 {
+            memory_bus.hblank_started();
+        }
     }
 
     fn set_color_palette(&mut self, pal_value: u8) {
@@ -155,25 +331,243 @@ impl PPU {
         };
     }
 
-    fn render_background(&mut self,  memory_bus: &mut Memory) {
-        if !self.control_reg.bg_enable {
+    fn render_background(&mut self, line: u8, memory_bus: &Memory, background: &mut [BackgroundPixel]) {
+        let cgb = memory_bus.is_cgb();
+
+        // On the DMG this bit blanks the background and window, on the CGB
+        // it instead takes priority away from them.
+        if !cgb && !self.control_reg.bg_enable {
+            for x in 0..SCREEN_WIDTH as usize {
+                self.set_pixel(x, line, colour(0));
+                self.set_shade(x, line, 0);
+            }
             return;
         }
+
+        let scroll_x = memory_bus.read_internal(SCX);
+        let scroll_y = memory_bus.read_internal(SCY);
+        let window_x = memory_bus.read_internal(WX) as usize;
+        let window_y = memory_bus.read_internal(WY);
+
+        let window_visible = self.control_reg.window_display_enable && line >= window_y && window_x <= 166;
+
+        let bg_map = if self.control_reg.bg_tile_map_display { TILE_MAP_HIGH } else { TILE_MAP_LOW };
+        let win_map = if self.control_reg.win_tile_map_display { TILE_MAP_HIGH } else { TILE_MAP_LOW };
+
+        for (x, background_pixel) in background.iter_mut().enumerate() {
+            let (map, map_x, map_y) = if window_visible && x + 7 >= window_x {
+                (win_map, (x + 7 - window_x) as u8, self.window_line)
+            }
+            else {
+                (bg_map, scroll_x.wrapping_add(x as u8), scroll_y.wrapping_add(line))
+            };
+
+            let map_addr = map + (map_y as usize / 8) * 32 + map_x as usize / 8;
+            let tile_number = memory_bus.read_vram(0, map_addr);
+            let attributes = if cgb { memory_bus.read_vram(1, map_addr) } else { 0 };
+
+            let tile_x = if attributes & ATTR_X_FLIP != 0 { 7 - map_x % 8 } else { map_x % 8 };
+            let tile_y = if attributes & ATTR_Y_FLIP != 0 { 7 - map_y % 8 } else { map_y % 8 };
+            let bank = ((attributes & ATTR_BANK) >> 3) as usize;
+
+            let tile_addr = self.tile_data_addr(tile_number);
+            let colour_index = tile_colour(memory_bus, bank, tile_addr, tile_x, tile_y);
+
+            *background_pixel = BackgroundPixel {
+                colour: colour_index,
+                priority: attributes & ATTR_PRIORITY != 0,
+            };
+
+            let pixel = if cgb {
+                cgb_colour(memory_bus.bg_palettes().colour(attributes & ATTR_PALETTE, colour_index))
+            }
+            else if memory_bus.has_compatibility_palettes() {
+                let shade = dmg_shade(memory_bus.read_internal(COLOUR_ADDR), colour_index);
+                cgb_colour(memory_bus.bg_palettes().colour(0, shade))
+            }
+            else {
+                self.set_shade(x, line, dmg_shade(memory_bus.read_internal(COLOUR_ADDR), colour_index));
+                self.bg_colour(colour_index)
+            };
+            self.set_pixel(x, line, pixel);
+        }
+
+        if window_visible {
+            self.window_line += 1;
+        }
+    }
+
+    fn render_sprites(&mut self, line: u8, memory_bus: &Memory, background: &[BackgroundPixel]) {
+        let cgb = memory_bus.is_cgb();
+        let height: i16 = if self.control_reg.sprite_size { 16 } else { 8 };
+        let line = line as i16;
+
+        let mut sprites: Vec<Sprite> = (0..OAM_ENTRIES)
+            .map(|entry| {
+                let addr = OAM_ADDR + entry * 4;
+
+                Sprite {
+                    y: memory_bus.read_internal(addr) as i16 - 16,
+                    x: memory_bus.read_internal(addr + 1) as i16 - 8,
+                    tile: memory_bus.read_internal(addr + 2),
+                    attributes: memory_bus.read_internal(addr + 3),
+                }
+            })
+            .filter(|sprite| line >= sprite.y && line < sprite.y + height)
+            .take(SPRITES_PER_LINE)
+            .collect();
+
+        // The DMG favours the sprite furthest left, the CGB goes by OAM order
+        if !cgb {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        let mut drawn = [false; SCREEN_WIDTH as usize];
+
+        for sprite in &sprites {
+            let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+            let mut tile_y = (line - sprite.y) as u8;
+            if sprite.attributes & ATTR_Y_FLIP != 0 {
+                tile_y = height as u8 - 1 - tile_y;
+            }
+
+            let bank = if cgb { ((sprite.attributes & ATTR_BANK) >> 3) as usize } else { 0 };
+            let tile_addr = TILE_DATA_UNSIGNED + tile as usize * 16;
+
+            for pixel_x in 0..8 {
+                let x = sprite.x + pixel_x;
+                if x < 0 || x >= SCREEN_WIDTH as i16 || drawn[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+
+                let tile_x = if sprite.attributes & ATTR_X_FLIP != 0 { 7 - pixel_x as u8 } else { pixel_x as u8 };
+                let colour_index = tile_colour(memory_bus, bank, tile_addr, tile_x, tile_y);
+                if colour_index == 0 {
+                    continue;
+                }
+
+                // The first opaque sprite pixel wins even if the background
+                // then hides it
+                drawn[x] = true;
+
+                let bg_master_priority = !cgb || self.control_reg.bg_enable;
+                let behind_bg = sprite.attributes & ATTR_PRIORITY != 0 || (cgb && background[x].priority);
+                if bg_master_priority && behind_bg && background[x].colour != 0 {
+                    continue;
+                }
+
+                let pixel = if cgb {
+                    cgb_colour(memory_bus.obj_palettes().colour(sprite.attributes & ATTR_PALETTE, colour_index))
+                }
+                else {
+                    let obp1 = sprite.attributes & ATTR_DMG_PALETTE != 0;
+                    let palette = memory_bus.read_internal(if obp1 { OBP1 } else { OBP0 });
+
+                    if memory_bus.has_compatibility_palettes() {
+                        cgb_colour(memory_bus.obj_palettes().colour(obp1 as u8, dmg_shade(palette, colour_index)))
+                    }
+                    else {
+                        self.set_shade(x, line as u8, dmg_shade(palette, colour_index));
+                        dmg_colour(palette, colour_index)
+                    }
+                };
+                self.set_pixel(x, line as u8, pixel);
+            }
+        }
+    }
+
+    fn tile_data_addr(&self, tile_number: u8) -> usize {
+        if self.control_reg.bg_win_tile_data {
+            TILE_DATA_UNSIGNED + tile_number as usize * 16
+        }
+        else {
+            (TILE_DATA_SIGNED as isize + (tile_number as i8) as isize * 16) as usize
+        }
+    }
+
+    fn bg_colour(&self, colour_index: u8) -> pixels::Color {
+        match colour_index {
+            0 => self.colour_zero,
+            1 => self.colour_one,
+            2 => self.colour_two,
+            _ => self.colour_three,
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, line: u8, pixel: pixels::Color) {
+        let offset = (line as usize * SCREEN_WIDTH as usize + x) * 3;
+        self.frame[offset] = pixel.r;
+        self.frame[offset + 1] = pixel.g;
+        self.frame[offset + 2] = pixel.b;
+    }
+
+    fn set_shade(&mut self, x: usize, line: u8, shade: u8) {
+        self.shades[line as usize * SCREEN_WIDTH as usize + x] = shade;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        if let Some(canvas) = self.canvas.as_mut() {
+            // Only fails for titles containing a NUL
+            let _ = canvas.window_mut().set_title(title);
+        }
     }
 
     pub fn display(&mut self) {
-        self.canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
-        self.canvas.clear();
-        self.canvas.present();
+        if let Some(canvas) = self.canvas.as_mut() {
+            let (width, height) = output_size(self.sgb_frame.is_some());
+            let frame = self.sgb_frame.as_ref().unwrap_or(&self.frame);
+
+            let texture_creator = canvas.texture_creator();
+            let mut texture = texture_creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+                .unwrap();
+
+            texture.update(None, frame, width as usize * 3).unwrap();
+
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+    }
+}
+
+fn output_size(sgb: bool) -> (u32, u32) {
+    if sgb {
+        (sgb::SCREEN_WIDTH as u32, sgb::SCREEN_HEIGHT as u32)
+    }
+    else {
+        (SCREEN_WIDTH, SCREEN_HEIGHT)
     }
 }
 
+// Returns the 2-bit colour index of a pixel within a tile.
+pub fn tile_colour(memory_bus: &Memory, bank: usize, tile_addr: usize, x: u8, y: u8) -> u8 {
+    let line_addr = tile_addr + y as usize * 2;
+    let low = memory_bus.read_vram(bank, line_addr);
+    let high = memory_bus.read_vram(bank, line_addr + 1);
+    let bit = 7 - x;
+
+    (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01)
+}
+
+pub fn dmg_shade(palette: u8, colour_index: u8) -> u8 {
+    (palette >> (colour_index * 2)) & 0x03
+}
+
+fn dmg_colour(palette: u8, colour_index: u8) -> pixels::Color {
+    colour(dmg_shade(palette, colour_index))
+}
+
+fn cgb_colour((r, g, b): (u8, u8, u8)) -> pixels::Color {
+    pixels::Color::RGB(r, g, b)
+}
+
 fn get_bit(value: u8, offset: u8, bit_value: u8) -> bool {
     let ret_val = (value & bit_value) >> offset;
     ret_val != 0
 }
 
-fn colour(value: u8) -> pixels::Color {
+pub fn colour(value: u8) -> pixels::Color {
     match value {
         0x00 => pixels::Color::RGB(255, 255, 255),
         0x01 => pixels::Color::RGB(205, 205, 205),
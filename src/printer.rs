@@ -0,0 +1,343 @@
+use std::path::PathBuf;
+
+use crate::png;
+use crate::serial::SerialDevice;
+
+const MAGIC_ONE: u8 = 0x88;
+const MAGIC_TWO: u8 = 0x33;
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// The printer holds 8KB of image data, enough for a 160x200 picture
+const BUFFER_SIZE: usize = 0x2000;
+const PRINT_WIDTH: usize = 160;
+const TILE_ROW_BYTES: usize = PRINT_WIDTH / 8 * 16;
+// Number of status requests the printer reports itself busy for after a print
+const PRINT_BUSY_POLLS: u8 = 4;
+const MARGIN_LINE_HEIGHT: usize = 16;
+
+const PAPER_SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+// Some games send a zero palette and rely on the printer's default
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+enum PacketState {
+    MagicOne,
+    MagicTwo,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+pub struct Printer {
+    output_dir: PathBuf,
+    prints: u32,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    image: Vec<u8>,
+    status: u8,
+    busy_polls: u8,
+}
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Self {
+        Printer {
+            output_dir,
+            prints: 0,
+            state: PacketState::MagicOne,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            image: Vec::new(),
+            status: 0,
+            busy_polls: 0,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            PacketState::MagicOne => {
+                if byte == MAGIC_ONE {
+                    self.state = PacketState::MagicTwo;
+                }
+            }
+            PacketState::MagicTwo => {
+                self.state = if byte == MAGIC_TWO { PacketState::Command } else { PacketState::MagicOne };
+            }
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthLow;
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthHigh;
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                self.state = if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data };
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                self.state = PacketState::ChecksumHigh;
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.state = PacketState::DeviceId;
+            }
+            PacketState::DeviceId => {
+                self.finish_packet();
+                self.state = PacketState::Status;
+                return DEVICE_ID;
+            }
+            PacketState::Status => {
+                self.state = PacketState::MagicOne;
+                return self.status;
+            }
+        }
+
+        0x00
+    }
+
+    fn finish_packet(&mut self) {
+        if self.received_checksum != self.checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.image.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.image.len();
+                self.image.extend(data.into_iter().take(space));
+
+                if !self.image.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.image.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT => {
+                if self.data.len() == 4 {
+                    let (margins, palette) = (self.data[1], self.data[2]);
+
+                    if let Err(err) = self.print(margins, palette) {
+                        eprintln!("Printer: {}", err);
+                    }
+                }
+
+                self.image.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.status |= STATUS_PRINTING;
+                self.busy_polls = PRINT_BUSY_POLLS;
+            }
+            COMMAND_STATUS if self.busy_polls > 0 => {
+                self.busy_polls -= 1;
+
+                if self.busy_polls == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The upper nibble of the margins is the number of line feeds before
+    // the image and the lower nibble the number after it.
+    fn print(&mut self, margins: u8, palette: u8) -> Result<(), String> {
+        let palette = if palette == 0 { DEFAULT_PALETTE } else { palette };
+        let before = (margins >> 4) as usize * MARGIN_LINE_HEIGHT;
+        let after = (margins & 0x0F) as usize * MARGIN_LINE_HEIGHT;
+        let image_height = self.image.len() / TILE_ROW_BYTES * 8;
+        let height = before + image_height + after;
+
+        if height == 0 {
+            return Ok(());
+        }
+
+        let mut pixels = vec![PAPER_SHADES[0]; PRINT_WIDTH * height];
+
+        for y in 0..image_height {
+            for x in 0..PRINT_WIDTH {
+                let tile = (y / 8) * (PRINT_WIDTH / 8) + x / 8;
+                let line = tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+
+                let low = (self.image[line] >> bit) & 0x01;
+                let high = (self.image[line + 1] >> bit) & 0x01;
+                let colour = (high << 1) | low;
+                let shade = (palette >> (colour * 2)) & 0x03;
+
+                pixels[(before + y) * PRINT_WIDTH + x] = PAPER_SHADES[shade as usize];
+            }
+        }
+
+        // Prints from earlier sessions are left alone
+        let path = loop {
+            self.prints += 1;
+            let path = self.output_dir.join(format!("print-{:03}.png", self.prints));
+            if !path.exists() {
+                break path;
+            }
+        };
+        png::write_greyscale(&path, PRINT_WIDTH as u32, height as u32, &pixels)?;
+
+        println!("Printed {}", path.display());
+        Ok(())
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+}
+
+// Run length encoding: a control byte with bit 7 set repeats the following
+// byte (control & 0x7F) + 2 times, otherwise the next (control + 1) bytes
+// are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut index = 0;
+
+    while index < data.len() {
+        let control = data[index];
+        index += 1;
+
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(index) {
+                let count = (control & 0x7F) as usize + 2;
+                output.extend(std::iter::repeat_n(byte, count));
+            }
+            index += 1;
+        }
+        else {
+            let end = (index + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    // Sends a packet, returning the device ID and status bytes sent back.
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8], checksum_error: u16) -> (u8, u8) {
+        let mut packet = vec![MAGIC_ONE, MAGIC_TWO, command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+
+        let checksum = packet[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&(checksum ^ checksum_error).to_le_bytes());
+
+        for byte in packet {
+            assert_eq!(printer.exchange(byte), 0);
+        }
+        (printer.exchange(0), printer.exchange(0))
+    }
+
+    #[test]
+    fn reports_its_status() {
+        let mut printer = Printer::new(PathBuf::new());
+
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[], 0), (DEVICE_ID, 0));
+        // The status sent back already includes the packet's data
+        assert_eq!(send(&mut printer, COMMAND_DATA, false, &[0xFF; 16], 0), (DEVICE_ID, STATUS_UNPROCESSED));
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[], 0), (DEVICE_ID, 0));
+    }
+
+    #[test]
+    fn waits_for_the_magic_bytes() {
+        let mut printer = Printer::new(PathBuf::new());
+        for byte in [0x00, MAGIC_ONE, 0x00, 0x42] {
+            printer.exchange(byte);
+        }
+
+        assert_eq!(send(&mut printer, COMMAND_DATA, false, &[0x12; 4], 0), (DEVICE_ID, STATUS_UNPROCESSED));
+        assert_eq!(printer.image, [0x12; 4]);
+    }
+
+    #[test]
+    fn packets_with_a_bad_checksum_are_dropped() {
+        let mut printer = Printer::new(PathBuf::new());
+
+        assert_eq!(send(&mut printer, COMMAND_DATA, false, &[0x12; 4], 1).1, STATUS_CHECKSUM_ERROR);
+        assert!(printer.image.is_empty());
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[], 0).1, 0);
+    }
+
+    #[test]
+    fn compressed_data_is_expanded() {
+        let mut printer = Printer::new(PathBuf::new());
+        send(&mut printer, COMMAND_DATA, true, &[0x81, 0xAA, 0x01, 0x12, 0x34], 0);
+
+        assert_eq!(printer.image, [0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn prints_skip_names_already_taken() {
+        let dir = env::temp_dir().join(format!("dustboy-printer-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("print-001.png"), b"earlier").unwrap();
+
+        let mut printer = Printer::new(dir.clone());
+        send(&mut printer, COMMAND_DATA, false, &[0x00; TILE_ROW_BYTES], 0);
+        send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x00, DEFAULT_PALETTE, 0x40], 0);
+
+        assert_eq!(fs::read(dir.join("print-001.png")).unwrap(), b"earlier");
+        assert!(dir.join("print-002.png").exists());
+        assert_ne!(send(&mut printer, COMMAND_STATUS, false, &[], 0).1 & STATUS_PRINTING, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
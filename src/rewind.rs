@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::memory::Memory;
+use crate::ppu::PPU;
+use crate::state;
+
+// Snapshots are kept as the newest full state plus a chain of XOR deltas
+// going back in time. Each delta is run-length encoded, which keeps them
+// small since most of memory doesn't change between snapshots.
+pub struct RewindBuffer {
+    interval: u32,
+    capacity: usize,
+    frames: u32,
+    latest: Option<Vec<u8>>,
+    // Whether the machine was last put back to `latest` rather than run on from it
+    restored: bool,
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        RewindBuffer {
+            interval,
+            capacity,
+            frames: 0,
+            latest: None,
+            restored: false,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    // Called once per emulated frame, takes a snapshot every `interval` frames.
+    pub fn capture(&mut self, cpu: &CPU, memory: &Memory, ppu: &PPU) {
+        if self.restored {
+            self.restored = false;
+            self.frames = 0;
+        }
+
+        self.frames += 1;
+
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let snapshot = state::save_state(cpu, memory, ppu);
+
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == snapshot.len() {
+                let delta = encode_delta(&latest, &snapshot);
+                self.deltas_size += delta.len();
+                self.deltas.push_back(delta);
+            }
+            else {
+                self.clear_deltas();
+            }
+        }
+
+        let latest_size = snapshot.len();
+        self.latest = Some(snapshot);
+
+        while latest_size + self.deltas_size > self.capacity {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break
+            }
+        }
+    }
+
+    // Called once per displayed frame while rewinding. The newest snapshot is
+    // restored first, then older ones every `interval` frames so rewinding
+    // runs at the speed the game played. Returns false once the buffer has
+    // run out.
+    pub fn step_back(&mut self, cpu: &mut CPU, memory: &mut Memory, ppu: &mut PPU) -> Result<bool, String> {
+        if self.restored {
+            self.frames += 1;
+            if self.frames < self.interval {
+                return Ok(!self.deltas.is_empty());
+            }
+
+            let delta = match self.deltas.pop_back() {
+                Some(delta) => delta,
+                None => return Ok(false)
+            };
+            self.deltas_size -= delta.len();
+
+            let latest = self.latest.as_mut().expect("rewind deltas without a snapshot");
+            apply_delta(latest, &delta);
+        }
+
+        let latest = match self.latest.as_ref() {
+            Some(latest) => latest,
+            None => return Ok(false)
+        };
+        self.frames = 0;
+        self.restored = true;
+
+        state::load_state(latest, cpu, memory, ppu)?;
+
+        Ok(true)
+    }
+
+    fn clear_deltas(&mut self) {
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+}
+
+// Delta format: repeated (zero run, literal length, literal bytes) with the
+// lengths stored as LEB128 varints.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = old.iter().zip(new).map(|(a, b)| a ^ b).collect();
+    let mut delta = Vec::new();
+    let mut index = 0;
+
+    while index < xor.len() {
+        let zero_start = index;
+        while index < xor.len() && xor[index] == 0 {
+            index += 1;
+        }
+
+        let literal_start = index;
+        while index < xor.len() && xor[index] != 0 {
+            index += 1;
+        }
+
+        write_varint(&mut delta, literal_start - zero_start);
+        write_varint(&mut delta, index - literal_start);
+        delta.extend_from_slice(&xor[literal_start..index]);
+    }
+
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut index = 0;
+
+    while index < delta.len() {
+        position += read_varint(delta, &mut index);
+        let literal_length = read_varint(delta, &mut index);
+
+        for byte in &delta[index..index + literal_length] {
+            state[position] ^= byte;
+            position += 1;
+        }
+        index += literal_length;
+    }
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+fn read_varint(input: &[u8], index: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = input[*index];
+        *index += 1;
+
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let old: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut new = old.clone();
+        new[0] = 0xAA;
+        new[500..510].fill(0);
+        new[999] ^= 0x01;
+
+        let delta = encode_delta(&new, &old);
+        assert!(delta.len() < 32);
+
+        let mut state = new.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, old);
+    }
+
+    #[test]
+    fn unchanged_state_has_an_empty_literal() {
+        let state = vec![0x12; 300];
+        let delta = encode_delta(&state, &state);
+
+        let mut restored = state.clone();
+        apply_delta(&mut restored, &delta);
+        assert_eq!(restored, state);
+        assert_eq!(delta, [0xAC, 0x02, 0x00]);
+    }
+
+    const MARKER: usize = 0xC000;
+
+    // Runs frames that only write their number to WRAM
+    fn marked_frames(rewind: &mut RewindBuffer, cpu: &CPU, memory: &mut Memory, ppu: &PPU, count: u8) {
+        for frame in 1..=count {
+            memory.write_internal(MARKER, frame);
+            rewind.capture(cpu, memory, ppu);
+        }
+    }
+
+    #[test]
+    fn steps_back_from_the_newest_snapshot_at_game_speed() {
+        let (mut cpu, mut memory, mut ppu) = (CPU::new(), Memory::new(), PPU::new(None, false));
+        let mut rewind = RewindBuffer::new(2, usize::MAX);
+
+        // Snapshots are taken on frames 2, 4 and 6
+        marked_frames(&mut rewind, &cpu, &mut memory, &ppu, 7);
+
+        let mut restored = Vec::new();
+        while rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap() {
+            restored.push(memory.read_internal(MARKER));
+        }
+        assert_eq!(restored, [6, 6, 4, 4, 2]);
+    }
+
+    #[test]
+    fn runs_on_from_where_rewinding_stopped() {
+        let (mut cpu, mut memory, mut ppu) = (CPU::new(), Memory::new(), PPU::new(None, false));
+        let mut rewind = RewindBuffer::new(1, usize::MAX);
+
+        marked_frames(&mut rewind, &cpu, &mut memory, &ppu, 3);
+        rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap();
+        rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap();
+        assert_eq!(memory.read_internal(MARKER), 2);
+
+        marked_frames(&mut rewind, &cpu, &mut memory, &ppu, 1);
+        rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap();
+        assert_eq!(memory.read_internal(MARKER), 1);
+        rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap();
+        assert_eq!(memory.read_internal(MARKER), 2);
+        rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap();
+        assert_eq!(memory.read_internal(MARKER), 1);
+        assert!(!rewind.step_back(&mut cpu, &mut memory, &mut ppu).unwrap());
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut output = Vec::new();
+        for &value in &[0, 1, 127, 128, 300, 1 << 20] {
+            write_varint(&mut output, value);
+        }
+
+        let mut index = 0;
+        for &value in &[0, 1, 127, 128, 300, 1 << 20] {
+            assert_eq!(read_varint(&output, &mut index), value);
+        }
+        assert_eq!(index, output.len());
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::cheats::{Cheat, Code};
+use crate::memory::Memory;
+use crate::options::parse_hex;
+
+const WRAM_START: u16 = 0xC000;
+const WRAM_BANKED_START: u16 = 0xD000;
+const WRAM_BANK_SIZE: u16 = 0x1000;
+const CART_RAM_START: u16 = 0xA000;
+const CART_RAM_SIZE: u16 = 0x2000;
+const HRAM_START: u16 = 0xFF80;
+const HRAM_SIZE: u16 = 0x7F;
+const CART_RAM_SIZE_HEADER: usize = 0x0149;
+const CGB_WRAM_BANKS: usize = 7;
+const LISTED_CANDIDATES: usize = 20;
+
+const HELP: &str = "\
+start [8|16]             snapshot RAM and search 8 or 16-bit values
+eq                       keep values unchanged since the last snapshot
+ne                       keep values that changed
+inc                      keep values that increased
+dec                      keep values that decreased
+value <n>                keep values equal to n ($ or 0x for hex)
+list                     show the candidates left
+watch <n>                print candidate n whenever it changes
+cheat <n> [value]        freeze candidate n with a GameShark code";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Word,
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+// An address in one of the searched regions, with the WRAM bank for
+// addresses from 0xD000.
+#[derive(Clone, Copy, PartialEq)]
+struct Location {
+    bank: Option<usize>,
+    addr: u16,
+}
+
+impl Location {
+    fn read_byte(&self, memory: &Memory) -> u8 {
+        match self.bank {
+            Some(bank) => memory.read_wram(bank, (self.addr - WRAM_BANKED_START) as usize),
+            None => memory.read_internal(self.addr as usize),
+        }
+    }
+
+    fn read(&self, size: Size, memory: &Memory) -> u16 {
+        let low = self.read_byte(memory) as u16;
+        match size {
+            Size::Byte => low,
+            Size::Word => low | (Location { addr: self.addr + 1, ..*self }.read_byte(memory) as u16) << 8,
+        }
+    }
+
+    fn format(&self) -> String {
+        match self.bank {
+            Some(bank) => format!("{:02X}:{:04X}", bank, self.addr),
+            None => format!("{:04X}", self.addr),
+        }
+    }
+}
+
+struct Candidate {
+    location: Location,
+    previous: u16,
+}
+
+// Keeps the size it was added with, later searches can use another
+struct Watch {
+    location: Location,
+    size: Size,
+    value: u16,
+}
+
+// Narrows down where a game keeps a value by comparing snapshots of WRAM,
+// HRAM and cartridge RAM. Commands are typed on the terminal while the game
+// keeps running.
+pub struct RamSearch {
+    commands: Receiver<String>,
+    size: Size,
+    candidates: Vec<Candidate>,
+    watches: Vec<Watch>,
+}
+
+impl RamSearch {
+    pub fn new() -> Self {
+        let (sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("RAM search, type help for commands");
+
+        RamSearch {
+            commands,
+            size: Size::Byte,
+            candidates: Vec::new(),
+            watches: Vec::new(),
+        }
+    }
+
+    // Runs any commands typed since the last frame and reports watched
+    // values that changed. Cheats can only be added while `cheats_allowed`.
+    pub fn update(&mut self, memory: &mut Memory, cheats_allowed: bool) {
+        while let Ok(line) = self.commands.try_recv() {
+            if let Err(err) = self.run_command(&line, memory, cheats_allowed) {
+                println!("{}", err);
+            }
+        }
+
+        for watch in &mut self.watches {
+            let current = watch.location.read(watch.size, memory);
+            if current != watch.value {
+                println!("{}: {} -> {}", watch.location.format(), format_value(watch.value, watch.size), format_value(current, watch.size));
+                watch.value = current;
+            }
+        }
+    }
+
+    fn run_command(&mut self, line: &str, memory: &mut Memory, cheats_allowed: bool) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(());
+        };
+        let arguments: Vec<&str> = words.collect();
+
+        match command {
+            "help" | "h" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            "start" => {
+                self.size = match arguments.first().copied() {
+                    None | Some("8") => Size::Byte,
+                    Some("16") => Size::Word,
+                    Some(size) => return Err(format!("Invalid size {}, expected 8 or 16", size)),
+                };
+                self.start(memory);
+                Ok(())
+            }
+            "eq" => self.filter(Comparison::Equal, memory),
+            "ne" => self.filter(Comparison::Changed, memory),
+            "inc" => self.filter(Comparison::Increased, memory),
+            "dec" => self.filter(Comparison::Decreased, memory),
+            "value" | "v" => {
+                let value = arguments.first().ok_or("Expected a value")?;
+                self.filter(Comparison::Value(parse_value(value, self.size)?), memory)
+            }
+            "list" | "l" => {
+                self.list(memory);
+                Ok(())
+            }
+            "watch" | "w" => {
+                let location = self.candidate(&arguments)?;
+                let size = self.size;
+                self.watches.push(Watch { location, size, value: location.read(size, memory) });
+                println!("Watching {}", location.format());
+                Ok(())
+            }
+            "cheat" if !cheats_allowed => Err("Cheats can't be added while a movie is running".to_string()),
+            "cheat" => self.add_cheat(&arguments, memory),
+            _ => Err(format!("Unknown command {}, try help", command)),
+        }
+    }
+
+    fn start(&mut self, memory: &Memory) {
+        let size = self.size;
+        self.candidates = searched_ranges(memory)
+            .into_iter()
+            .flat_map(|(bank, start, length)| {
+                // A 16-bit value must fit inside the range
+                let end = start + length - if size == Size::Word { 1 } else { 0 };
+                (start..end).map(move |addr| Location { bank, addr })
+            })
+            .map(|location| Candidate { location, previous: location.read(size, memory) })
+            .collect();
+
+        println!("{} candidates", self.candidates.len());
+    }
+
+    fn filter(&mut self, comparison: Comparison, memory: &Memory) -> Result<(), String> {
+        if self.candidates.is_empty() {
+            return Err("No candidates, use start first".to_string());
+        }
+
+        let size = self.size;
+        self.candidates.retain_mut(|candidate| {
+            let current = candidate.location.read(size, memory);
+            let keep = match comparison {
+                Comparison::Equal => current == candidate.previous,
+                Comparison::Changed => current != candidate.previous,
+                Comparison::Increased => current > candidate.previous,
+                Comparison::Decreased => current < candidate.previous,
+                Comparison::Value(value) => current == value,
+            };
+            candidate.previous = current;
+            keep
+        });
+
+        println!("{} candidates", self.candidates.len());
+        if self.candidates.len() <= LISTED_CANDIDATES {
+            self.list(memory);
+        }
+        Ok(())
+    }
+
+    fn list(&self, memory: &Memory) {
+        for (index, candidate) in self.candidates.iter().take(LISTED_CANDIDATES).enumerate() {
+            let current = candidate.location.read(self.size, memory);
+            println!("{}: {} = {}", index, candidate.location.format(), format_value(current, self.size));
+        }
+
+        if self.candidates.len() > LISTED_CANDIDATES {
+            println!("... and {} more", self.candidates.len() - LISTED_CANDIDATES);
+        }
+    }
+
+    fn candidate(&self, arguments: &[&str]) -> Result<Location, String> {
+        let index = arguments.first().ok_or("Expected a candidate number")?;
+        index.parse::<usize>().ok()
+            .and_then(|index| self.candidates.get(index))
+            .map(|candidate| candidate.location)
+            .ok_or_else(|| format!("No candidate {}", index))
+    }
+
+    // Freezes the candidate at its current value, or the one given, with a
+    // GameShark code for each byte.
+    fn add_cheat(&mut self, arguments: &[&str], memory: &mut Memory) -> Result<(), String> {
+        let location = self.candidate(arguments)?;
+        let value = match arguments.get(1) {
+            Some(value) => parse_value(value, self.size)?,
+            None => location.read(self.size, memory),
+        };
+
+        let bytes = match self.size {
+            Size::Byte => vec![value as u8],
+            Size::Word => value.to_le_bytes().to_vec(),
+        };
+
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let code = Code::GameShark { bank: location.bank, addr: location.addr + offset as u16, value: byte };
+            println!("Added {}", code);
+            memory.cheats_mut().add(Cheat { code, name: code.to_string(), enabled: true });
+        }
+        Ok(())
+    }
+}
+
+// Start, length and WRAM bank of each region searched.
+fn searched_ranges(memory: &Memory) -> Vec<(Option<usize>, u16, u16)> {
+    let mut ranges = vec![(None, WRAM_START, WRAM_BANK_SIZE)];
+
+    let banks = if memory.is_cgb() { CGB_WRAM_BANKS } else { 1 };
+    ranges.extend((1..=banks).map(|bank| (Some(bank), WRAM_BANKED_START, WRAM_BANK_SIZE)));
+
+    if memory.cartridge().get(CART_RAM_SIZE_HEADER).is_some_and(|&size| size != 0) {
+        ranges.push((None, CART_RAM_START, CART_RAM_SIZE));
+    }
+
+    ranges.push((None, HRAM_START, HRAM_SIZE));
+    ranges
+}
+
+fn format_value(value: u16, size: Size) -> String {
+    match size {
+        Size::Byte => format!("{:02X} ({})", value, value),
+        Size::Word => format!("{:04X} ({})", value, value),
+    }
+}
+
+// Values are decimal unless they start with $ or 0x, and have to fit the
+// size being searched.
+fn parse_value(value: &str, size: Size) -> Result<u16, String> {
+    let parsed = if value.starts_with('$') || value.starts_with("0x") { parse_hex(value) } else { value.parse().ok() };
+    let parsed = parsed.ok_or_else(|| format!("Invalid value {}", value))?;
+
+    if size == Size::Byte && parsed > 0xFF {
+        return Err(format!("{} doesn't fit in 8 bits, use start 16 for larger values", value));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(size: Size, memory: &Memory) -> RamSearch {
+        let mut search = RamSearch { commands: mpsc::channel().1, size, candidates: Vec::new(), watches: Vec::new() };
+        search.start(memory);
+        search
+    }
+
+    fn candidates(search: &RamSearch) -> Vec<String> {
+        search.candidates.iter().map(|candidate| candidate.location.format()).collect()
+    }
+
+    #[test]
+    fn narrows_down_a_byte() {
+        let mut memory = Memory::new();
+        let mut search = started(Size::Byte, &memory);
+
+        memory.write_internal(0xC123, 5);
+        search.filter(Comparison::Increased, &memory).unwrap();
+        assert_eq!(candidates(&search), ["C123"]);
+
+        memory.write_internal(0xC123, 3);
+        search.filter(Comparison::Equal, &memory).unwrap();
+        assert!(search.candidates.is_empty());
+
+        let mut search = started(Size::Byte, &memory);
+        memory.write_internal(0xC123, 2);
+        memory.write_internal(0xFF90, 9);
+        search.filter(Comparison::Changed, &memory).unwrap();
+        assert_eq!(candidates(&search), ["C123", "FF90"]);
+
+        search.filter(Comparison::Value(9), &memory).unwrap();
+        assert_eq!(candidates(&search), ["FF90"]);
+
+        memory.write_internal(0xFF90, 8);
+        search.filter(Comparison::Decreased, &memory).unwrap();
+        assert_eq!(candidates(&search), ["FF90"]);
+    }
+
+    #[test]
+    fn narrows_down_a_word() {
+        let mut memory = Memory::new();
+        let mut search = started(Size::Word, &memory);
+
+        memory.write_internal(0xC000, 0x34);
+        memory.write_internal(0xC001, 0x12);
+        search.filter(Comparison::Changed, &memory).unwrap();
+        assert_eq!(candidates(&search), ["C000", "C001"]);
+
+        search.run_command("value $1234", &mut memory, true).unwrap();
+        assert_eq!(candidates(&search), ["C000"]);
+    }
+
+    #[test]
+    fn banked_wram_is_searched_by_bank() {
+        let mut memory = Memory::new();
+        let bytes = started(Size::Byte, &memory);
+        let words = started(Size::Word, &memory);
+
+        // Words can't run off the end of a bank
+        assert!(candidates(&bytes).contains(&"01:DFFF".to_string()));
+        assert!(!candidates(&words).contains(&"01:DFFF".to_string()));
+        assert!(!candidates(&words).contains(&"CFFF".to_string()));
+
+        let mut search = bytes;
+        memory.write_wram(1, 0x0FFF, 7);
+        search.filter(Comparison::Changed, &memory).unwrap();
+        assert_eq!(candidates(&search), ["01:DFFF"]);
+    }
+
+    #[test]
+    fn values_must_fit_the_size() {
+        let mut memory = Memory::new();
+        let mut search = started(Size::Byte, &memory);
+
+        assert!(search.run_command("value 300", &mut memory, true).is_err());
+        assert!(search.run_command("cheat 0 $100", &mut memory, true).is_err());
+        assert!(!memory.has_cheats());
+        assert_eq!(parse_value("$FF", Size::Byte), Ok(0xFF));
+        assert_eq!(parse_value("300", Size::Word), Ok(300));
+        assert!(parse_value("70000", Size::Word).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::Write;
+
+use crate::state::{StateReader, StateWriter};
+
+pub const SB: usize = 0xFF01;
+pub const SC: usize = 0xFF02;
+
+const TRANSFER_START: u8 = 0x80;
+const INTERNAL_CLOCK: u8 = 0x01;
+const SC_UNUSED_BITS: u8 = 0x7E;
+
+// Internal clock runs at 8192 Hz, one bit every 512 cycles
+const CYCLES_PER_BIT: u32 = 512;
+const BITS_PER_TRANSFER: u32 = 8;
+pub const TRANSFER_CYCLES: u32 = CYCLES_PER_BIT * BITS_PER_TRANSFER;
+
+pub struct PortState {
+    pub data: u8,
+    // Transfer in progress using our own clock
+    pub internal_transfer: bool,
+    // Waiting for the other side to clock a transfer
+    pub external_transfer: bool,
+}
+
+pub trait SerialDevice {
+    // Shifts a byte out to the device and returns the byte shifted back in.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+
+    // Called as emulated time passes. Devices which provide the clock return
+    // the byte to shift in when they complete an external clock transfer.
+    fn sync(&mut self, _ticks: u32, _port: &PortState) -> Option<u8> {
+        None
+    }
+}
+
+// With no cable attached the input line is pulled high.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    cycles: u32,
+    device: Box<dyn SerialDevice>,
+    capture: Option<Box<dyn Write>>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            cycles: 0,
+            device: Box::new(Disconnected),
+            capture: None,
+        }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn set_capture(&mut self, capture: Box<dyn Write>) {
+        self.capture = Some(capture);
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    pub fn read_control(&self) -> u8 {
+        self.control | SC_UNUSED_BITS
+    }
+
+    // Rewriting SC during a transfer doesn't restart it.
+    pub fn write_control(&mut self, value: u8) {
+        if self.control & TRANSFER_START == 0 && value & TRANSFER_START != 0 {
+            self.cycles = 0;
+        }
+        self.control = value & (TRANSFER_START | INTERNAL_CLOCK);
+    }
+
+    // Advances the current transfer, returns true when it completes and the
+    // serial interrupt should be raised.
+    pub fn step(&mut self, ticks: u32) -> bool {
+        let port = PortState {
+            data: self.data,
+            internal_transfer: self.control == TRANSFER_START | INTERNAL_CLOCK,
+            external_transfer: self.control == TRANSFER_START,
+        };
+
+        let incoming = self.device.sync(ticks, &port);
+
+        if port.external_transfer {
+            if let Some(incoming) = incoming {
+                self.complete_transfer(incoming);
+                return true;
+            }
+        }
+
+        if !port.internal_transfer {
+            return false;
+        }
+
+        self.cycles += ticks;
+        if self.cycles < TRANSFER_CYCLES {
+            return false;
+        }
+
+        let incoming = self.device.exchange(self.data);
+        self.complete_transfer(incoming);
+
+        true
+    }
+
+    fn complete_transfer(&mut self, incoming: u8) {
+        if let Some(capture) = self.capture.as_mut() {
+            // Capture is best effort, a failed write shouldn't stop the game
+            let _ = capture.write_all(&[self.data]).and_then(|_| capture.flush());
+        }
+
+        self.data = incoming;
+        self.control &= !TRANSFER_START;
+        self.cycles = 0;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.cycles = reader.read_u32()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_transfer_takes_eight_bits() {
+        let mut serial = Serial::new();
+        serial.write_data(0x42);
+        serial.write_control(TRANSFER_START | INTERNAL_CLOCK);
+
+        assert!(!serial.step(TRANSFER_CYCLES - 1));
+        assert!(serial.step(1));
+        assert_eq!(serial.read_data(), 0xFF);
+        assert_eq!(serial.read_control() & TRANSFER_START, 0);
+    }
+
+    #[test]
+    fn rewriting_control_keeps_the_transfer_going() {
+        let mut serial = Serial::new();
+        serial.write_control(TRANSFER_START | INTERNAL_CLOCK);
+        serial.step(TRANSFER_CYCLES / 2);
+
+        serial.write_control(TRANSFER_START | INTERNAL_CLOCK);
+        assert!(serial.step(TRANSFER_CYCLES / 2));
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_other_side() {
+        let mut serial = Serial::new();
+        serial.write_control(TRANSFER_START);
+
+        assert!(!serial.step(TRANSFER_CYCLES * 2));
+        assert_ne!(serial.read_control() & TRANSFER_START, 0);
+    }
+}
@@ -0,0 +1,535 @@
+use crate::palette::rgb555_to_rgb888;
+use crate::state::{StateReader, StateWriter};
+
+// The SNES picture, with the game screen in the middle of the border
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 224;
+const GAME_WIDTH: usize = 160;
+const GAME_HEIGHT: usize = 144;
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+
+// 0x03 at 0x146 enables the SGB functions, but only with the new licensee code
+const SGB_FLAG: usize = 0x0146;
+const OLD_LICENSEE: usize = 0x014B;
+const SGB_SUPPORTED: u8 = 0x03;
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const MASK_NONE: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOUR_ZERO: u8 = 3;
+
+// Palettes are given per 8x8 cell of the game screen
+const CELLS_WIDE: usize = 20;
+const CELLS_HIGH: usize = 18;
+const CELLS: usize = CELLS_WIDE * CELLS_HIGH;
+
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS / 4;
+
+pub const TRANSFER_SIZE: usize = 0x1000;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+const BORDER_MAP_SIZE: usize = 0x800;
+const BORDER_PALETTES: usize = 4;
+const BORDER_PALETTE_COLOURS: usize = 16;
+// The border uses SNES palettes 4 to 7
+const FIRST_BORDER_PALETTE: usize = 4;
+
+// The SGB's default palette, used until a game sets its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+// Data the game shows on screen for the SGB to copy out of VRAM
+pub enum Transfer {
+    SystemPalettes,
+    BorderTiles(bool),
+    BorderMap,
+    AttributeFiles,
+}
+
+pub struct Sgb {
+    enabled: bool,
+    receiving: bool,
+    bit: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    packets_left: u8,
+    previous_select: u8,
+    players: u8,
+    current_player: u8,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: [u8; CELLS],
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: [[u16; BORDER_PALETTE_COLOURS]; BORDER_PALETTES],
+    mask: u8,
+}
+
+impl Sgb {
+    pub fn new(cartridge: &[u8]) -> Self {
+        let enabled = cartridge.get(SGB_FLAG) == Some(&SGB_SUPPORTED)
+            && cartridge.get(OLD_LICENSEE) == Some(&USE_NEW_LICENSEE);
+
+        Sgb {
+            enabled,
+            receiving: false,
+            bit: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            packets_left: 0,
+            previous_select: 0x30,
+            players: 1,
+            current_player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; CELLS],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            border_tiles: vec![0; TRANSFER_SIZE * 2],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; BORDER_PALETTE_COLOURS]; BORDER_PALETTES],
+            mask: MASK_NONE,
+        }
+    }
+
+    // Packets are sent a bit at a time through P14 and P15. Pulling both low
+    // starts a packet, then P14 low sends a 0 and P15 low a 1, with both
+    // lines going high again between bits. Returns a transfer for the caller
+    // to fill in from VRAM once a command asks for one.
+    pub fn write_p1(&mut self, value: u8) -> Option<Transfer> {
+        let select = value & 0x30;
+        let previous = self.previous_select;
+        self.previous_select = select;
+
+        // With several players, each time P15 goes high the next joypad
+        // is selected
+        if !self.receiving && select & 0x20 != 0 && previous & 0x20 == 0 {
+            self.current_player = (self.current_player + 1) % self.players;
+        }
+
+        if !self.enabled || previous != 0x30 {
+            return None;
+        }
+
+        match select {
+            0x00 => {
+                self.receiving = true;
+                self.bit = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if self.receiving => {
+                if self.bit == PACKET_BITS {
+                    // Stop bit
+                    self.receiving = false;
+                    return self.receive_packet();
+                }
+
+                if select == 0x10 {
+                    self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                }
+                self.bit += 1;
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    // With MLT_REQ the joypad number shows in P1 while no buttons are
+    // selected. Only the first joypad is connected, the others never have
+    // anything pressed.
+    pub fn joypad(&self, select: u8) -> Option<u8> {
+        if self.players == 1 {
+            None
+        }
+        else if select & 0x30 == 0x30 {
+            Some(0x0F - self.current_player)
+        }
+        else if self.current_player != 0 {
+            Some(0x0F)
+        }
+        else {
+            None
+        }
+    }
+
+    fn receive_packet(&mut self) -> Option<Transfer> {
+        if self.command.is_empty() {
+            self.packets_left = (self.packet[0] & 0x07).max(1);
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+
+        if self.packets_left > 0 {
+            return None;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        self.execute(&command)
+    }
+
+    fn execute(&mut self, data: &[u8]) -> Option<Transfer> {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_divide(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => self.set_system_palettes(data),
+            PAL_TRN => return Some(Transfer::SystemPalettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            CHR_TRN => return Some(Transfer::BorderTiles(data[1] & 0x01 != 0)),
+            PCT_TRN => return Some(Transfer::BorderMap),
+            ATTR_TRN => return Some(Transfer::AttributeFiles),
+            ATTR_SET => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = MASK_NONE;
+                }
+            }
+            MASK_EN => self.mask = data[1] & 0x03,
+            // Sound and SNES program commands have nothing to drive here
+            _ => {}
+        }
+
+        None
+    }
+
+    pub fn complete_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::SystemPalettes => {
+                for (colour, bytes) in self.system_palettes.iter_mut().zip(data.chunks_exact(2)) {
+                    *colour = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            Transfer::BorderTiles(upper) => {
+                let start = if upper { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data[..TRANSFER_SIZE]);
+            }
+            Transfer::BorderMap => {
+                self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+
+                let colours = data[BORDER_MAP_SIZE..].chunks_exact(2);
+                for (index, bytes) in colours.take(BORDER_PALETTES * BORDER_PALETTE_COLOURS).enumerate() {
+                    let colour = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    self.border_palettes[index / BORDER_PALETTE_COLOURS][index % BORDER_PALETTE_COLOURS] = colour;
+                }
+            }
+            Transfer::AttributeFiles => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let colour = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+
+        for index in 1..4 {
+            self.palettes[first][index] = colour(index);
+            self.palettes[second][index] = colour(index + 3);
+        }
+        self.set_shared_colour(colour(0));
+    }
+
+    // Colour 0 is shared by all four palettes
+    fn set_shared_colour(&mut self, colour: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour;
+        }
+    }
+
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let number = u16::from_le_bytes([data[1 + palette * 2], data[2 + palette * 2]]) as usize % SYSTEM_PALETTES;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+        }
+        self.set_shared_colour(self.palettes[0][0]);
+
+        let flags = data[9];
+        if flags & 0x80 != 0 {
+            self.apply_attribute_file(flags & 0x3F);
+        }
+        if flags & 0x40 != 0 {
+            self.mask = MASK_NONE;
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_WIDE && y < CELLS_HIGH {
+            self.attributes[y * CELLS_WIDE + x] = palette & 0x03;
+        }
+    }
+
+    // Each block gives palettes for the cells inside it, on its edge and
+    // outside of it.
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for block in data[2..].chunks_exact(6).take(count) {
+            let mut control = block[0] & 0x07;
+            let inside = block[1] & 0x03;
+            let outside = (block[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+
+            // With only the inside or outside given the edge goes along with it
+            let edge = match control {
+                0x01 => { control |= 0x02; inside }
+                0x04 => { control |= 0x02; outside }
+                _ => (block[1] >> 2) & 0x03,
+            };
+
+            for y in 0..CELLS_HIGH {
+                for x in 0..CELLS_WIDE {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    if on_edge && control & 0x02 != 0 {
+                        self.set_attribute(x, y, edge);
+                    }
+                    else if within && !on_edge && control & 0x01 != 0 {
+                        self.set_attribute(x, y, inside);
+                    }
+                    else if !within && control & 0x04 != 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for &line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+
+            if line & 0x80 != 0 {
+                for x in 0..CELLS_WIDE {
+                    self.set_attribute(x, number, palette);
+                }
+            }
+            else {
+                for y in 0..CELLS_HIGH {
+                    self.set_attribute(number, y, palette);
+                }
+            }
+        }
+    }
+
+    // Splits the screen along one row or column, with palettes for either
+    // side and the line itself.
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+
+        for y in 0..CELLS_HIGH {
+            for x in 0..CELLS_WIDE {
+                let position = if horizontal { y } else { x };
+                let palette = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    // Palettes for a run of cells, packed four to a byte.
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+
+        for index in 0..count.min(CELLS) {
+            let Some(&byte) = data.get(6 + index / 4) else {
+                break;
+            };
+            self.set_attribute(x, y, byte >> (6 - (index % 4) * 2));
+
+            if vertical {
+                y += 1;
+                if y == CELLS_HIGH {
+                    y = 0;
+                    x += 1;
+                }
+            }
+            else {
+                x += 1;
+                if x == CELLS_WIDE {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        for cell in 0..CELLS {
+            let byte = self.attribute_files[start + cell / 4];
+            self.attributes[cell] = (byte >> (6 - (cell % 4) * 2)) & 0x03;
+        }
+    }
+
+    // Colours the game screen's shades by cell and draws it inside the border.
+    pub fn render(&self, shades: &[u8], frame: &mut [u8]) {
+        if self.mask == MASK_FREEZE {
+            return;
+        }
+
+        let backdrop = self.palettes[0][0];
+        let mut set_pixel = |x: usize, y: usize, colour: u16| {
+            let (r, g, b) = rgb555_to_rgb888(colour);
+            let offset = (y * SCREEN_WIDTH + x) * 3;
+            frame[offset..offset + 3].copy_from_slice(&[r, g, b]);
+        };
+
+        for y in 0..GAME_HEIGHT {
+            for x in 0..GAME_WIDTH {
+                let colour = match self.mask {
+                    MASK_BLACK => 0x0000,
+                    MASK_COLOUR_ZERO => backdrop,
+                    _ => {
+                        let palette = self.attributes[(y / 8) * CELLS_WIDE + x / 8] as usize;
+                        self.palettes[palette][shades[y * GAME_WIDTH + x] as usize]
+                    }
+                };
+                set_pixel(GAME_X + x, GAME_Y + y, colour);
+            }
+        }
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let in_game = (GAME_X..GAME_X + GAME_WIDTH).contains(&x) && (GAME_Y..GAME_Y + GAME_HEIGHT).contains(&y);
+                if in_game {
+                    continue;
+                }
+
+                let colour = self.border_colour(x, y).unwrap_or(backdrop);
+                set_pixel(x, y, colour);
+            }
+        }
+    }
+
+    // Border tiles are SNES 4 bits per pixel tiles, colour 0 is transparent.
+    fn border_colour(&self, x: usize, y: usize) -> Option<u16> {
+        let entry_offset = ((y / 8) * BORDER_MAP_WIDTH + x / 8) * 2;
+        if y / 8 >= BORDER_MAP_HEIGHT {
+            return None;
+        }
+        let entry = u16::from_le_bytes([self.border_map[entry_offset], self.border_map[entry_offset + 1]]);
+
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let tile_x = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let tile_y = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+
+        let row = tile * BORDER_TILE_SIZE + tile_y * 2;
+        let bit = 7 - tile_x;
+        let plane = |offset: usize| (self.border_tiles[row + offset] >> bit) & 0x01;
+        let colour = plane(0) | plane(1) << 1 | plane(16) << 2 | plane(17) << 3;
+
+        if colour == 0 {
+            return None;
+        }
+
+        let palette = palette.saturating_sub(FIRST_BORDER_PALETTE) % BORDER_PALETTES;
+        Some(self.border_palettes[palette][colour as usize])
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.receiving);
+        writer.write_u8(self.bit as u8);
+        writer.write_bytes(&self.packet);
+        writer.write_u8(self.command.len() as u8);
+        writer.write_bytes(&self.command);
+        writer.write_u8(self.packets_left);
+        writer.write_u8(self.previous_select);
+        writer.write_u8(self.players);
+        writer.write_u8(self.current_player);
+
+        for colour in self.palettes.iter().flatten().chain(&self.system_palettes) {
+            writer.write_u16(*colour);
+        }
+        writer.write_bytes(&self.attributes);
+        writer.write_bytes(&self.attribute_files);
+        writer.write_bytes(&self.border_tiles);
+        writer.write_bytes(&self.border_map);
+        for colour in self.border_palettes.iter().flatten() {
+            writer.write_u16(*colour);
+        }
+        writer.write_u8(self.mask);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.receiving = reader.read_bool()?;
+        self.bit = (reader.read_u8()? as usize).min(PACKET_BITS);
+        self.packet.copy_from_slice(reader.read_bytes(PACKET_SIZE)?);
+        let command_length = reader.read_u8()? as usize;
+        self.command = reader.read_bytes(command_length)?.to_vec();
+        self.packets_left = reader.read_u8()?;
+        self.previous_select = reader.read_u8()?;
+        self.players = reader.read_u8()?.max(1);
+        self.current_player = reader.read_u8()? % self.players;
+
+        for colour in self.palettes.iter_mut().flatten().chain(self.system_palettes.iter_mut()) {
+            *colour = reader.read_u16()?;
+        }
+        self.attributes.copy_from_slice(reader.read_bytes(CELLS)?);
+        self.attribute_files.copy_from_slice(reader.read_bytes(ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE)?);
+        self.border_tiles.copy_from_slice(reader.read_bytes(TRANSFER_SIZE * 2)?);
+        self.border_map.copy_from_slice(reader.read_bytes(BORDER_MAP_SIZE)?);
+        for colour in self.border_palettes.iter_mut().flatten() {
+            *colour = reader.read_u16()?;
+        }
+        self.mask = reader.read_u8()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 70224 cycles per frame at 4194304 Hz
+const FRAME_NANOS: u64 = 16_742_706;
+const SLOW_MOTION_FACTOR: u32 = 4;
+const PAUSE_POLL_MILLIS: u64 = 10;
+
+#[derive(Clone, Copy)]
+pub enum FastForward {
+    Multiplier(u32),
+    Uncapped,
+}
+
+pub struct FrameLimiter {
+    fast_forward: FastForward,
+    fast_forwarding: bool,
+    slow_motion: bool,
+    paused: bool,
+    advance_frame: bool,
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(fast_forward: FastForward) -> Self {
+        FrameLimiter {
+            fast_forward,
+            fast_forwarding: false,
+            slow_motion: false,
+            paused: false,
+            advance_frame: false,
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn set_fast_forward(&mut self, enabled: bool) {
+        self.fast_forwarding = enabled;
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.slow_motion = !self.slow_motion;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance_frame = false;
+        self.next_frame = Instant::now();
+    }
+
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance_frame = true;
+        }
+    }
+
+    // Returns whether the emulator should run a frame now, sleeping briefly
+    // while paused so the event loop doesn't spin.
+    pub fn should_run_frame(&mut self) -> bool {
+        if !self.paused {
+            return true;
+        }
+
+        if self.advance_frame {
+            self.advance_frame = false;
+            return true;
+        }
+
+        thread::sleep(Duration::from_millis(PAUSE_POLL_MILLIS));
+        false
+    }
+
+    pub fn wait(&mut self) {
+        let frame_duration = match self.frame_duration() {
+            Some(duration) => duration,
+            None => {
+                self.next_frame = Instant::now();
+                return;
+            }
+        };
+
+        self.next_frame += frame_duration;
+
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        }
+        else {
+            // Running behind, don't try to catch up on lost frames
+            self.next_frame = now;
+        }
+    }
+
+    fn frame_duration(&self) -> Option<Duration> {
+        let frame = Duration::from_nanos(FRAME_NANOS);
+
+        if self.fast_forwarding {
+            return match self.fast_forward {
+                FastForward::Multiplier(multiplier) => Some(frame / multiplier),
+                FastForward::Uncapped => None,
+            };
+        }
+
+        if self.slow_motion {
+            return Some(frame * SLOW_MOTION_FACTOR);
+        }
+
+        Some(frame)
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+use crate::memory::Memory;
+use crate::ppu::PPU;
+
+const MAGIC: &[u8; 4] = b"DBST";
+const VERSION: u16 = 9;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.position + length > self.data.len() {
+            return Err("Save state is truncated".to_string());
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+// Layout: magic, version (u16), ROM checksum (u32), payload length (u32),
+// followed by the CPU, memory and PPU sections.
+pub fn save_state(cpu: &CPU, memory: &Memory, ppu: &PPU) -> Vec<u8> {
+    let mut payload = StateWriter::new();
+    cpu.save_state(&mut payload);
+    memory.save_state(&mut payload);
+    ppu.save_state(&mut payload);
+    let payload = payload.into_bytes();
+
+    let mut writer = StateWriter::new();
+    writer.write_bytes(MAGIC);
+    writer.write_u16(VERSION);
+    writer.write_u32(memory.rom_checksum());
+    writer.write_u32(payload.len() as u32);
+    writer.write_bytes(&payload);
+
+    writer.into_bytes()
+}
+
+pub fn load_state(data: &[u8], cpu: &mut CPU, memory: &mut Memory, ppu: &mut PPU) -> Result<(), String> {
+    let mut reader = StateReader::new(data);
+
+    if reader.read_bytes(MAGIC.len())? != MAGIC {
+        return Err("Not a dustboy save state".to_string());
+    }
+
+    let version = reader.read_u16()?;
+    if version != VERSION {
+        return Err(format!("Unsupported save state version {}", version));
+    }
+
+    if reader.read_u32()? != memory.rom_checksum() {
+        return Err("Save state was made for a different ROM".to_string());
+    }
+
+    // Check the length up front so a truncated file can't leave the
+    // machine half loaded.
+    let payload_length = reader.read_u32()? as usize;
+    if reader.remaining() != payload_length {
+        return Err("Save state is truncated".to_string());
+    }
+
+    cpu.load_state(&mut reader)?;
+    memory.load_state(&mut reader)?;
+    ppu.load_state(&mut reader)?;
+
+    Ok(())
+}
+
+// Slots are named after the ROM and its checksum, `game-1A2B3C4D.ss1`, so
+// ROMs sharing a name in different archives don't share states.
+pub fn slot_path(rom_path: &str, rom_checksum: u32, slot: u8) -> PathBuf {
+    let path = Path::new(rom_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-{:08X}.ss{}", stem, rom_checksum, slot))
+}
+
+pub fn save_slot(path: &Path, cpu: &CPU, memory: &Memory, ppu: &PPU) -> Result<(), String> {
+    fs::write(path, save_state(cpu, memory, ppu)).map_err(|e| e.to_string())
+}
+
+pub fn load_slot(path: &Path, cpu: &mut CPU, memory: &mut Memory, ppu: &mut PPU) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    load_state(&data, cpu, memory, ppu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{CONTROL_REG, WX, WY};
+
+    // A machine partway through a frame with the window showing
+    fn running() -> (CPU, Memory, PPU) {
+        let (mut cpu, mut memory, mut ppu) = (CPU::new(), Memory::new(), PPU::new(None, false));
+        memory.write_internal(0xC000, 0x42);
+        memory.write_internal(CONTROL_REG, 0xA1);
+        memory.write_internal(WY, 0);
+        memory.write_internal(WX, 7);
+
+        for _ in 0..100 {
+            cpu.execute_opcode(&mut memory);
+        }
+        ppu.step(456 * 20 + 100, &mut memory);
+
+        (cpu, memory, ppu)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (cpu, memory, ppu) = running();
+        let saved = save_state(&cpu, &memory, &ppu);
+
+        let (mut cpu, mut memory, mut ppu) = (CPU::new(), Memory::new(), PPU::new(None, false));
+        load_state(&saved, &mut cpu, &mut memory, &mut ppu).unwrap();
+
+        assert_eq!(memory.read_internal(0xC000), 0x42);
+        assert_eq!(save_state(&cpu, &memory, &ppu), saved);
+    }
+
+    #[test]
+    fn states_for_other_roms_are_refused() {
+        let (cpu, memory, ppu) = running();
+        let mut saved = save_state(&cpu, &memory, &ppu);
+        saved[MAGIC.len() + 2] ^= 0xFF;
+
+        let (mut cpu, mut memory, mut ppu) = (CPU::new(), Memory::new(), PPU::new(None, false));
+        assert_eq!(load_state(&saved, &mut cpu, &mut memory, &mut ppu).unwrap_err(), "Save state was made for a different ROM");
+        assert_eq!(memory.read_internal(0xC000), 0);
+    }
+}
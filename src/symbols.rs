@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::memory::Memory;
+use crate::options::parse_hex;
+
+// Labels from an RGBDS symbol file, lines of `bank:address name`.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    // Ordered so that a lookup in any bank finds the lowest one
+    labels: BTreeMap<(usize, u16), String>,
+    addresses: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut symbols = Symbols::default();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line.split_once(char::is_whitespace).and_then(|(location, name)| {
+                let (bank, addr) = location.split_once(':')?;
+                Some((usize::from_str_radix(bank, 16).ok()?, parse_hex(addr)?, name.trim()))
+            });
+
+            let Some((bank, addr, name)) = parsed else {
+                return Err(format!("Invalid symbol {}", line));
+            };
+
+            // Several labels can share an address, the first one is shown
+            symbols.labels.entry((bank, addr)).or_insert_with(|| name.to_string());
+            symbols.addresses.insert(name.to_string(), (bank, addr));
+        }
+
+        Ok(symbols)
+    }
+
+    // Loads game.sym for game.gb if there is one.
+    pub fn next_to(rom_path: &str) -> Symbols {
+        let path = Path::new(rom_path).with_extension("sym");
+        if !path.exists() {
+            return Symbols::default();
+        }
+
+        Symbols::load(&path).unwrap_or_else(|err| {
+            eprintln!("Symbols: {}: {}", path.display(), err);
+            Symbols::default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // The bank must match for ROM addresses. RAM labels are matched in any
+    // bank since only ROM banking is tracked, the lowest bank winning.
+    pub fn label(&self, bank: Option<usize>, addr: u16) -> Option<&str> {
+        match bank {
+            Some(bank) => self.labels.get(&(bank, addr)),
+            None => self.labels.iter().find(|((_, label_addr), _)| *label_addr == addr).map(|(_, name)| name),
+        }
+        .map(|name| name.as_str())
+    }
+
+    // Looks addr up in whichever bank is mapped there right now.
+    pub fn mapped_label(&self, memory: &Memory, addr: u16) -> Option<&str> {
+        if self.is_empty() {
+            return None;
+        }
+
+        self.label(memory.rom_bank(addr), addr)
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<(usize, u16)> {
+        self.addresses.get(name).copied()
+    }
+
+    // Accepts a label or a hexadecimal address. Labels win, since a label
+    // like `Add` would also read as hex.
+    pub fn parse_address(&self, value: &str) -> Option<u16> {
+        self.resolve(value).map(|(_, addr)| addr).or_else(|| parse_hex(value))
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::cpu::CPU;
+use crate::disassembler;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+
+// Which instructions end up in the log
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    pub range: Option<(u16, u16)>,
+    pub bank: Option<usize>,
+    // Logging begins when PC reaches start and pauses when it reaches stop
+    pub start: Option<u16>,
+    pub stop: Option<u16>,
+    pub disassemble: bool,
+    // Labels from the symbol file, which other emulators' logs won't have
+    pub labels: bool,
+}
+
+impl TraceFilter {
+    pub fn is_set(&self) -> bool {
+        self.range.is_some() || self.bank.is_some() || self.start.is_some() || self.stop.is_some() || self.disassemble || self.labels
+    }
+}
+
+// Writes a line per instruction in the format used by Gameboy Doctor, so
+// logs can be compared with other emulators. Labels and disassembly are
+// opt-in and go in a comment at the end of the line.
+pub struct Tracer {
+    output: Option<BufWriter<File>>,
+    filter: TraceFilter,
+    symbols: Symbols,
+    active: bool,
+}
+
+impl Tracer {
+    pub fn new(path: &str, filter: TraceFilter, symbols: Symbols) -> Result<Tracer, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+
+        Ok(Tracer {
+            output: Some(BufWriter::new(file)),
+            active: filter.start.is_none(),
+            filter,
+            symbols,
+        })
+    }
+
+    // Called before each instruction runs.
+    pub fn log(&mut self, cpu: &CPU, memory: &Memory) {
+        let registers = cpu.registers();
+        let pc = registers.pc;
+
+        if self.filter.stop == Some(pc) {
+            self.active = false;
+        }
+        else if self.filter.start == Some(pc) {
+            self.active = true;
+        }
+
+        let in_range = self.filter.range.is_none_or(|(start, end)| (start..=end).contains(&pc));
+        let in_bank = self.filter.bank.is_none_or(|bank| memory.rom_bank(pc) == Some(bank));
+        if !self.active || !in_range || !in_bank {
+            return;
+        }
+
+        let Some(output) = self.output.as_mut() else {
+            return;
+        };
+
+        let read = |addr: u16| memory.read_memory(addr as usize);
+        let [a, f] = registers.af.to_be_bytes();
+        let [b, c] = registers.bc.to_be_bytes();
+        let [d, e] = registers.de.to_be_bytes();
+        let [h, l] = registers.hl.to_be_bytes();
+
+        let mut line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            a, f, b, c, d, e, h, l,
+            memory.get_stack_pointer(),
+            pc,
+            read(pc),
+            read(pc.wrapping_add(1)),
+            read(pc.wrapping_add(2)),
+            read(pc.wrapping_add(3))
+        );
+
+        let symbols = &self.symbols;
+        let labels = self.filter.labels;
+        let label = |addr: u16| if labels { symbols.mapped_label(memory, addr) } else { None };
+        let instruction = self.filter.disassemble.then(|| {
+            disassembler::decode_with_labels(pc, read, |addr| label(addr).map(|name| name.to_string())).text
+        });
+
+        match (label(pc), instruction) {
+            (Some(label), Some(instruction)) => line.push_str(&format!(" ; {}: {}", label, instruction)),
+            (Some(label), None) => line.push_str(&format!(" ; {}", label)),
+            (None, Some(instruction)) => line.push_str(&format!(" ; {}", instruction)),
+            (None, None) => {}
+        }
+
+        if let Err(err) = writeln!(output, "{}", line) {
+            // Keep running without the log rather than stopping the game
+            eprintln!("Trace: {}", err);
+            self.output = None;
+        }
+    }
+}
@@ -0,0 +1,476 @@
+extern crate sdl2;
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use crate::memory::Memory;
+use crate::memory_viewer::MemoryViewer;
+use crate::options::Options;
+use crate::ppu::*;
+
+const SCALE: u32 = 2;
+
+const TILE_SIZE: usize = 8;
+const TILE_BYTES: usize = 16;
+const TILES_PER_ROW: usize = 16;
+const TILES_PER_BANK: usize = 384;
+const TILE_ROWS: usize = TILES_PER_BANK / TILES_PER_ROW;
+
+const MAP_SIZE: usize = 256;
+const MAP_TILES: usize = 32;
+const MAP_GAP: usize = 8;
+
+const OAM_ROWS: usize = 20;
+const OAM_COLUMNS: usize = OAM_ENTRIES / OAM_ROWS;
+const OAM_ROW_HEIGHT: usize = 18;
+const OAM_COLUMN_WIDTH: usize = 104;
+const OAM_TEXT_X: usize = 11;
+
+const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+pub const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+
+const DMG_BG_PALETTES: usize = 1;
+const DMG_OBJ_PALETTES: usize = 2;
+const CGB_PALETTES: usize = 8;
+
+const LCDC_SPRITE_SIZE: u8 = 0x04;
+const LCDC_BG_MAP: u8 = 0x08;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_WINDOW_MAP: u8 = 0x40;
+
+pub const BACKDROP: (u8, u8, u8) = (64, 64, 80);
+pub const TEXT: (u8, u8, u8) = (230, 230, 230);
+const VIEWPORT: (u8, u8, u8) = (255, 0, 0);
+const WINDOW_AREA: (u8, u8, u8) = (0, 96, 255);
+
+#[derive(Clone, Copy)]
+enum Palette {
+    Background(u8),
+    Object(u8),
+}
+
+// One debug window drawn a pixel at a time and scaled up when shown
+pub struct ViewerWindow {
+    canvas: Canvas<Window>,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl ViewerWindow {
+    pub fn new(sdl_context: &sdl2::Sdl, title: &str, width: usize, height: usize) -> Self {
+        let video_subsystem = sdl_context.video().unwrap();
+        let window = video_subsystem.window(title, width as u32 * SCALE, height as u32 * SCALE)
+                                    .build()
+                                    .unwrap();
+
+        ViewerWindow {
+            canvas: window.into_canvas().build().unwrap(),
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn clear(&mut self) {
+        for pixel in self.pixels.chunks_mut(3) {
+            pixel.copy_from_slice(&[BACKDROP.0, BACKDROP.1, BACKDROP.2]);
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        if x < self.width && y < self.height {
+            let offset = (y * self.width + x) * 3;
+            self.pixels[offset..offset + 3].copy_from_slice(&[r, g, b]);
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set_pixel(column, row, rgb);
+            }
+        }
+    }
+
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str) {
+        self.draw_text_in(x, y, text, TEXT);
+    }
+
+    pub fn draw_text_in(&mut self, x: usize, y: usize, text: &str, rgb: (u8, u8, u8)) {
+        for (index, character) in text.chars().enumerate() {
+            let rows = glyph(character.to_ascii_uppercase());
+
+            for (row, bits) in rows.iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (0x04 >> column) != 0 {
+                        self.set_pixel(x + index * GLYPH_ADVANCE + column, y + row, rgb);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn present(&mut self) {
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, self.width as u32, self.height as u32)
+            .unwrap();
+
+        texture.update(None, &self.pixels, self.width * 3).unwrap();
+
+        self.canvas.copy(&texture, None, None).unwrap();
+        self.canvas.present();
+    }
+}
+
+// Windows showing VRAM and OAM as they are, updated every frame.
+pub struct Viewers {
+    tiles: Option<ViewerWindow>,
+    maps: Option<ViewerWindow>,
+    oam: Option<ViewerWindow>,
+    memory: Option<MemoryViewer>,
+    // Which palette the tile window uses, changed with the arrow keys
+    palette: usize,
+    cgb: bool,
+}
+
+impl Viewers {
+    pub fn new(sdl_context: &sdl2::Sdl, options: &Options, cgb: bool) -> Self {
+        // The CGB has a second VRAM bank with another 384 tiles
+        let banks = if cgb { 2 } else { 1 };
+
+        Viewers {
+            tiles: options.tile_viewer.then(|| {
+                ViewerWindow::new(sdl_context, "dustboy tiles", TILES_PER_ROW * TILE_SIZE * banks, TILE_ROWS * TILE_SIZE)
+            }),
+            maps: options.map_viewer.then(|| ViewerWindow::new(sdl_context, "dustboy tile maps", MAP_SIZE * 2 + MAP_GAP, MAP_SIZE)),
+            oam: options.oam_viewer.then(|| {
+                ViewerWindow::new(sdl_context, "dustboy OAM", OAM_COLUMN_WIDTH * OAM_COLUMNS, OAM_ROW_HEIGHT * OAM_ROWS)
+            }),
+            memory: options.memory_viewer.then(|| MemoryViewer::new(sdl_context, cgb)),
+            palette: 0,
+            cgb,
+        }
+    }
+
+    // Returns true if the event belonged to one of the viewer windows.
+    // Memory can't be edited while `editable` is false.
+    pub fn handle_event(&mut self, event: &Event, memory: &mut Memory, editable: bool) -> bool {
+        let window_id = match *event {
+            Event::Window { window_id, .. } | Event::KeyDown { window_id, .. } | Event::KeyUp { window_id, .. } => window_id,
+            _ => return false,
+        };
+
+        if self.memory.as_ref().is_some_and(|viewer| viewer.id() == window_id) {
+            if let Event::Window { win_event: WindowEvent::Close, .. } = *event {
+                self.memory = None;
+            }
+            else if let Some(viewer) = self.memory.as_mut() {
+                viewer.handle_event(event, memory, editable);
+            }
+            return true;
+        }
+
+        let is_window = |viewer: &Option<ViewerWindow>| viewer.as_ref().is_some_and(|viewer| viewer.id() == window_id);
+        let tiles = is_window(&self.tiles);
+        if !tiles && !is_window(&self.maps) && !is_window(&self.oam) {
+            return false;
+        }
+
+        match *event {
+            Event::Window { win_event: WindowEvent::Close, .. } => {
+                for viewer in [&mut self.tiles, &mut self.maps, &mut self.oam] {
+                    if viewer.as_ref().is_some_and(|viewer| viewer.id() == window_id) {
+                        *viewer = None;
+                    }
+                }
+            }
+            Event::KeyDown { keycode: Some(Keycode::Right), .. } if tiles => {
+                self.palette = (self.palette + 1) % self.palette_count();
+            }
+            Event::KeyDown { keycode: Some(Keycode::Left), .. } if tiles => {
+                self.palette = (self.palette + self.palette_count() - 1) % self.palette_count();
+            }
+            _ => {}
+        }
+
+        true
+    }
+
+    pub fn update(&mut self, memory: &Memory) {
+        let palette = self.selected_palette();
+
+        if let Some(viewer) = self.tiles.as_mut() {
+            draw_tiles(viewer, memory, palette);
+            viewer.present();
+        }
+
+        if let Some(viewer) = self.maps.as_mut() {
+            draw_maps(viewer, memory);
+            viewer.present();
+        }
+
+        if let Some(viewer) = self.oam.as_mut() {
+            draw_oam(viewer, memory);
+            viewer.present();
+        }
+
+        if let Some(viewer) = self.memory.as_mut() {
+            viewer.update(memory);
+        }
+    }
+
+    fn palette_count(&self) -> usize {
+        if self.cgb { CGB_PALETTES * 2 } else { DMG_BG_PALETTES + DMG_OBJ_PALETTES }
+    }
+
+    // Background palettes come first, then object palettes
+    fn selected_palette(&self) -> Palette {
+        let background = if self.cgb { CGB_PALETTES } else { DMG_BG_PALETTES };
+
+        if self.palette < background {
+            Palette::Background(self.palette as u8)
+        }
+        else {
+            Palette::Object((self.palette - background) as u8)
+        }
+    }
+}
+
+fn palette_colour(memory: &Memory, palette: Palette, colour_index: u8) -> (u8, u8, u8) {
+    if memory.is_cgb() {
+        return match palette {
+            Palette::Background(number) => memory.bg_palettes().colour(number, colour_index),
+            Palette::Object(number) => memory.obj_palettes().colour(number, colour_index),
+        };
+    }
+
+    let register = match palette {
+        Palette::Background(_) => COLOUR_ADDR,
+        Palette::Object(0) => OBP0,
+        Palette::Object(_) => OBP1,
+    };
+    let shade = dmg_shade(memory.read_internal(register), colour_index);
+
+    if memory.has_compatibility_palettes() {
+        match palette {
+            Palette::Background(_) => memory.bg_palettes().colour(0, shade),
+            Palette::Object(number) => memory.obj_palettes().colour(number, shade),
+        }
+    }
+    else {
+        let pixel = colour(shade);
+        (pixel.r, pixel.g, pixel.b)
+    }
+}
+
+fn draw_tiles(viewer: &mut ViewerWindow, memory: &Memory, palette: Palette) {
+    let banks = viewer.width / (TILES_PER_ROW * TILE_SIZE);
+
+    for bank in 0..banks {
+        for tile in 0..TILES_PER_BANK {
+            let tile_addr = TILE_DATA_UNSIGNED + tile * TILE_BYTES;
+            let origin_x = (bank * TILES_PER_ROW + tile % TILES_PER_ROW) * TILE_SIZE;
+            let origin_y = (tile / TILES_PER_ROW) * TILE_SIZE;
+
+            for y in 0..TILE_SIZE {
+                for x in 0..TILE_SIZE {
+                    let colour_index = tile_colour(memory, bank, tile_addr, x as u8, y as u8);
+                    viewer.set_pixel(origin_x + x, origin_y + y, palette_colour(memory, palette, colour_index));
+                }
+            }
+        }
+    }
+}
+
+fn draw_maps(viewer: &mut ViewerWindow, memory: &Memory) {
+    let control = memory.read_internal(CONTROL_REG);
+    let cgb = memory.is_cgb();
+    viewer.clear();
+
+    for (index, &map) in [TILE_MAP_LOW, TILE_MAP_HIGH].iter().enumerate() {
+        let origin_x = index * (MAP_SIZE + MAP_GAP);
+
+        for map_y in 0..MAP_SIZE {
+            for map_x in 0..MAP_SIZE {
+                let map_addr = map + (map_y / TILE_SIZE) * MAP_TILES + map_x / TILE_SIZE;
+                let tile_number = memory.read_vram(0, map_addr);
+                let attributes = if cgb { memory.read_vram(1, map_addr) } else { 0 };
+
+                let tile_x = if attributes & ATTR_X_FLIP != 0 { 7 - map_x % 8 } else { map_x % 8 };
+                let tile_y = if attributes & ATTR_Y_FLIP != 0 { 7 - map_y % 8 } else { map_y % 8 };
+                let bank = ((attributes & ATTR_BANK) >> 3) as usize;
+
+                let tile_addr = if control & LCDC_TILE_DATA != 0 {
+                    TILE_DATA_UNSIGNED + tile_number as usize * TILE_BYTES
+                }
+                else {
+                    (TILE_DATA_SIGNED as isize + (tile_number as i8) as isize * TILE_BYTES as isize) as usize
+                };
+
+                let colour_index = tile_colour(memory, bank, tile_addr, tile_x as u8, tile_y as u8);
+                let pixel = palette_colour(memory, Palette::Background(attributes & ATTR_PALETTE), colour_index);
+                viewer.set_pixel(origin_x + map_x, map_y, pixel);
+            }
+        }
+    }
+
+    let map_origin = |high: bool| if high { MAP_SIZE + MAP_GAP } else { 0 };
+
+    // The part of the background map on screen, which wraps at the edges
+    let scroll_x = memory.read_internal(SCX) as usize;
+    let scroll_y = memory.read_internal(SCY) as usize;
+    let bg_origin = map_origin(control & LCDC_BG_MAP != 0);
+    draw_outline(viewer, bg_origin, scroll_x, scroll_y, SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize, VIEWPORT);
+
+    // The window always starts at the top left of its map
+    let window_x = memory.read_internal(WX) as usize;
+    let window_y = memory.read_internal(WY) as usize;
+    let window_shown = control & LCDC_WINDOW_ENABLE != 0 && window_x <= 166 && window_y < SCREEN_HEIGHT as usize;
+
+    if window_shown {
+        let hidden_left = 7usize.saturating_sub(window_x);
+        let width = SCREEN_WIDTH as usize + 7 - window_x.max(7);
+        let height = SCREEN_HEIGHT as usize - window_y;
+        let window_origin = map_origin(control & LCDC_WINDOW_MAP != 0);
+        draw_outline(viewer, window_origin, hidden_left, 0, width, height, WINDOW_AREA);
+    }
+}
+
+// Draws a rectangle on a map, wrapping around its edges as the PPU does.
+fn draw_outline(viewer: &mut ViewerWindow, origin: usize, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+    for offset in 0..width {
+        let column = origin + (x + offset) % MAP_SIZE;
+        viewer.set_pixel(column, y % MAP_SIZE, rgb);
+        viewer.set_pixel(column, (y + height - 1) % MAP_SIZE, rgb);
+    }
+
+    for offset in 0..height {
+        let row = (y + offset) % MAP_SIZE;
+        viewer.set_pixel(origin + x % MAP_SIZE, row, rgb);
+        viewer.set_pixel(origin + (x + width - 1) % MAP_SIZE, row, rgb);
+    }
+}
+
+// Each entry shows its number, Y, X, tile and flags next to the sprite.
+fn draw_oam(viewer: &mut ViewerWindow, memory: &Memory) {
+    let cgb = memory.is_cgb();
+    let tall = memory.read_internal(CONTROL_REG) & LCDC_SPRITE_SIZE != 0;
+    let height = if tall { TILE_SIZE * 2 } else { TILE_SIZE };
+    viewer.clear();
+
+    for entry in 0..OAM_ENTRIES {
+        let addr = OAM_ADDR + entry * 4;
+        let y = memory.read_internal(addr);
+        let x = memory.read_internal(addr + 1);
+        let tile = memory.read_internal(addr + 2);
+        let attributes = memory.read_internal(addr + 3);
+
+        let origin_x = (entry / OAM_ROWS) * OAM_COLUMN_WIDTH + 2;
+        let origin_y = (entry % OAM_ROWS) * OAM_ROW_HEIGHT + 1;
+
+        let palette = if cgb {
+            Palette::Object(attributes & ATTR_PALETTE)
+        }
+        else {
+            Palette::Object((attributes & ATTR_DMG_PALETTE != 0) as u8)
+        };
+        let bank = if cgb { ((attributes & ATTR_BANK) >> 3) as usize } else { 0 };
+        let tile = if tall { tile & 0xFE } else { tile };
+
+        for pixel_y in 0..height {
+            for pixel_x in 0..TILE_SIZE {
+                let tile_x = if attributes & ATTR_X_FLIP != 0 { 7 - pixel_x } else { pixel_x };
+                let tile_y = if attributes & ATTR_Y_FLIP != 0 { height - 1 - pixel_y } else { pixel_y };
+                let tile_addr = TILE_DATA_UNSIGNED + tile as usize * TILE_BYTES;
+
+                // Colour 0 is transparent, so it's left as the backdrop
+                let colour_index = tile_colour(memory, bank, tile_addr, tile_x as u8, tile_y as u8);
+                if colour_index != 0 {
+                    viewer.set_pixel(origin_x + pixel_x, origin_y + pixel_y, palette_colour(memory, palette, colour_index));
+                }
+            }
+        }
+
+        let text_x = origin_x + OAM_TEXT_X;
+        viewer.draw_text(text_x, origin_y, &format!("{:02X} Y:{:02X} X:{:02X}", entry, y, x));
+        viewer.draw_text(text_x, origin_y + GLYPH_HEIGHT + 2, &format!("   T:{:02X} F:{:02X}", tile, attributes));
+    }
+}
+
+// A 3x5 font with capitals, digits and common punctuation
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    match character {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '?' => [0b110, 0b001, 0b010, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '\\' => [0b100, 0b100, 0b010, 0b001, 0b001],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '"' => [0b101, 0b101, 0b000, 0b000, 0b000],
+        '*' => [0b000, 0b101, 0b010, 0b101, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}